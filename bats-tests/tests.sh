@@ -38,10 +38,14 @@ setup() {
 }
 
 @test "Should add secret" {
-  run bombadil add-secret -k "server_password" -v "hunter2" -f "$HOME/dotfiles/vars.toml"
-  assert_output --partial 'Added server_password : hunter2'
+  run bash -c 'echo hunter2 | bombadil add-secret -k "server_password" -f "$HOME/dotfiles/vars.toml"'
+  assert_output --partial 'Added secret server_password'
+  refute_output --partial 'hunter2'
 
   run bombadil get secrets
+  assert_output --partial '"server_password": "********"'
+
+  run bombadil get secrets --reveal
   assert_output --partial '"server_password": "hunter2"'
 
   run bombadil link
@@ -3,11 +3,9 @@ use clap::CommandFactory;
 use clap::Parser;
 use clap_complete::Shell;
 use std::io;
-use std::io::BufRead;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
 use toml_bombadil::settings::profiles;
-use toml_bombadil::{Bombadil, MetadataType, Mode};
+use toml_bombadil::{Bombadil, CloneOptions, MetadataType, Mode, SecretInput};
 
 macro_rules! fatal {
     ($($tt:tt)*) => {{
@@ -45,6 +43,15 @@ enum Cli {
         /// Force symlink creation even if the target already exists, a backup will be created
         #[arg(long, short)]
         force: bool,
+        /// Branch to checkout instead of the remote default branch
+        #[clap(short, long)]
+        branch: Option<String>,
+        /// Revision (commit, tag...) to checkout after cloning
+        #[clap(long)]
+        rev: Option<String>,
+        /// Create a shallow clone with the given number of commits
+        #[clap(long)]
+        depth: Option<i32>,
     },
     /// Symlink a copy of your dotfiles and inject variables according to bombadil.toml settings
     Link {
@@ -57,6 +64,12 @@ enum Cli {
     },
     /// Remove all symlinks defined in your bombadil.toml
     Unlink,
+    /// Fast-forward the dotfiles repository and link again with the previously enabled profiles
+    Pull {
+        /// Pull even if the dotfiles repository has uncommitted changes
+        #[arg(long, short)]
+        force: bool,
+    },
     /// Watch dotfiles and automatically run link on changes
     Watch {
         /// A list of comma-separated profiles to activate
@@ -66,20 +79,63 @@ enum Cli {
         #[arg(long, short)]
         force: bool,
     },
+    /// List declared packages missing on this system, and optionally install them
+    Packages {
+        /// A list of comma-separated profiles to activate
+        #[clap(short, long, required = false, value_parser = profiles(), num_args(0..))]
+        profiles: Vec<String>,
+        /// Install missing packages with the configured installer
+        #[arg(long, short)]
+        install: bool,
+    },
+    /// Show, commit and push changes to the dotfiles repository
+    Git {
+        #[command(subcommand)]
+        command: GitCommand,
+    },
     /// Add a secret var to bombadil environment
     AddSecret {
         /// Key of the secret variable to create
         #[clap(short, long)]
         key: String,
-        #[clap(short, long, required_unless_present = "ask")]
+        /// Value of the secret, prefer reading it from stdin to keep it out of your shell history
+        #[clap(short, long, conflicts_with_all = ["from_file", "from_command"])]
         value: Option<String>,
-        /// Get the secret value from stdin
-        #[clap(long, short)]
+        /// Read the secret value from a file
+        #[clap(long, conflicts_with = "from_command")]
+        from_file: Option<PathBuf>,
+        /// Read the secret value from the output of a shell command
+        #[clap(long)]
+        from_command: Option<String>,
+        /// Read the secret value from stdin, this is the default
+        #[clap(long, short, hide = true)]
         ask: bool,
         /// Path of the var file to modify
         #[clap(long, short)]
         file: String,
     },
+    /// Check the settings and every file they reference, exit with an error if anything is wrong
+    Check {
+        /// Decrypt secrets instead of rendering templates with a placeholder
+        #[clap(long)]
+        decrypt: bool,
+    },
+    /// Encrypt a whole file, to use it as a dot source decrypted on install
+    EncryptFile {
+        /// File to encrypt
+        file: PathBuf,
+        /// Encrypted file, defaults to the file name with a `.gpg` or `.age` extension in the dotfiles directory
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Encrypt with age for `age_recipients` instead of gpg
+        #[clap(long)]
+        age: bool,
+    },
+    /// List, remove, edit and re-encrypt secrets
+    Secret {
+        #[command(subcommand)]
+        command: SecretCommand,
+    },
     /// Get metadata about dots, hooks, path, profiles, or vars
     Get {
         #[clap(value_name = "VALUE", value_parser = ["dots", "prehooks", "posthooks", "path", "profiles", "vars", "secrets"])]
@@ -88,6 +144,9 @@ enum Cli {
         profiles: Vec<String>,
         #[clap(short, long)]
         no_color: bool,
+        /// Print decrypted secret values instead of masking them
+        #[clap(long)]
+        reveal: bool,
     },
     /// Generate shell completions
     /// Generate shell completions
@@ -98,6 +157,63 @@ enum Cli {
     },
 }
 
+#[derive(clap::Subcommand)]
+enum GitCommand {
+    /// Show dots with uncommitted source changes
+    Status,
+    /// Commit all changes, the default message lists the touched dots
+    Commit {
+        /// Commit message
+        #[clap(short, long)]
+        message: Option<String>,
+    },
+    /// Push the current branch
+    Push,
+}
+
+#[derive(clap::Subcommand)]
+enum SecretCommand {
+    /// List secret keys with their backend, for every var file by default
+    List {
+        /// Path of the var file to read
+        #[clap(long, short)]
+        file: Option<PathBuf>,
+    },
+    /// Show who can decrypt the secrets, for every var file by default
+    Recipients {
+        /// Path of the var file to read
+        #[clap(long, short)]
+        file: Option<PathBuf>,
+    },
+    /// Remove a secret
+    Remove {
+        /// Key of the secret to remove
+        #[clap(short, long)]
+        key: String,
+        /// Path of the var file to modify
+        #[clap(long, short)]
+        file: PathBuf,
+    },
+    /// Decrypt a secret in $EDITOR and encrypt it again
+    Edit {
+        /// Key of the secret to edit
+        #[clap(short, long)]
+        key: String,
+        /// Path of the var file to modify
+        #[clap(long, short)]
+        file: PathBuf,
+    },
+    /// Encrypt secrets again for other gpg user ids or age recipients, for every var file by default
+    Reencrypt {
+        /// The new recipients, the first one is the gpg user id
+        #[clap(short, long = "recipient", required = true)]
+        recipients: Vec<String>,
+        /// Path of the var file to modify
+        #[clap(long, short)]
+        file: Option<PathBuf>,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli: Cli = Cli::parse();
@@ -111,26 +227,19 @@ async fn main() -> Result<()> {
             target,
             profiles,
             force,
+            branch,
+            rev,
+            depth,
         } => {
-            let path = match target {
-                None => {
-                    let repo_name = remote.split('/').next_back().unwrap();
-                    let repo_name = repo_name.strip_suffix(".git").unwrap();
-                    PathBuf::from_str(repo_name).unwrap()
-                }
-                Some(path) => path,
-            };
-
-            println!("Cloning {remote} in {path:?}");
             let profiles: Option<Vec<&str>> = if !profiles.is_empty() {
-                // Remove this
-                let vec = profiles.iter().map(String::as_str).collect();
-                Some(vec)
+                Some(profiles.iter().map(String::as_str).collect())
             } else {
                 None
             };
 
-            Bombadil::install_from_remote(&remote, path, profiles, force)
+            let options = CloneOptions { branch, rev, depth };
+
+            Bombadil::install_from_remote(&remote, target, &options, profiles, force)
                 .unwrap_or_else(|err| fatal!("{}", err));
         }
         Cli::Link { profiles, force } => {
@@ -150,22 +259,53 @@ async fn main() -> Result<()> {
         }
         Cli::Unlink => {
             Bombadil::from_settings(Mode::NoGpg)
-                .and_then(|bombadil| bombadil.uninstall())
+                .and_then(|mut bombadil| bombadil.uninstall())
                 .unwrap_or_else(|err| fatal!("{}", err));
         }
+        Cli::Packages { profiles, install } => {
+            let mut bombadil =
+                Bombadil::from_settings(Mode::NoGpg).unwrap_or_else(|err| fatal!("{}", err));
+
+            bombadil
+                .enable_profiles(profiles.iter().map(String::as_str).collect())
+                .unwrap_or_else(|err| fatal!("{}", err));
+
+            let packages = if install {
+                bombadil.install_packages()
+            } else {
+                bombadil.missing_packages()
+            }
+            .unwrap_or_else(|err| fatal!("{}", err));
+
+            if packages.is_empty() {
+                println!("All packages are installed");
+            } else if !install {
+                println!("Missing packages:");
+                packages.iter().for_each(|package| println!("\t{package}"));
+            }
+        }
+        Cli::Pull { force } => {
+            Bombadil::pull(force).unwrap_or_else(|err| fatal!("{}", err));
+        }
+        Cli::Git { command } => {
+            let bombadil =
+                Bombadil::from_settings(Mode::NoGpg).unwrap_or_else(|err| fatal!("{}", err));
+
+            match command {
+                GitCommand::Status => bombadil.git_status(&mut io::stdout()),
+                GitCommand::Commit { message } => bombadil.git_commit(message.as_deref()),
+                GitCommand::Push => bombadil.git_push(),
+            }
+            .unwrap_or_else(|err| fatal!("{}", err));
+        }
         Cli::AddSecret {
             key,
             value,
-            ask,
+            from_file,
+            from_command,
+            ask: _,
             file,
         } => {
-            let value = if ask {
-                println!("Type the value and press enter to confirm :");
-                io::stdin().lock().lines().next().unwrap()?
-            } else {
-                value.expect("Missing value")
-            };
-
             let var_file = file;
             let path = Path::new(&var_file);
 
@@ -183,14 +323,61 @@ async fn main() -> Result<()> {
                 )
             }
 
+            let input = match (value, from_file, from_command) {
+                (Some(value), _, _) => SecretInput::Value(value),
+                (_, Some(path), _) => SecretInput::File(path),
+                (_, _, Some(command)) => SecretInput::Command(command),
+                _ => SecretInput::Stdin,
+            };
+
+            let value = input.read(&key).unwrap_or_else(|err| fatal!("{}", err));
+
             Bombadil::from_settings(Mode::Gpg)
                 .and_then(|bombadil| bombadil.add_secret(&key, &value, &var_file))
                 .unwrap_or_else(|err| fatal!("{}", err));
         }
+        Cli::Check { decrypt } => {
+            let problems = Bombadil::check(decrypt);
+            if problems.is_empty() {
+                println!("No problem found");
+            } else {
+                for problem in &problems {
+                    println!("{problem}");
+                }
+
+                fatal!("{} problem(s) found", problems.len());
+            }
+        }
+        Cli::EncryptFile { file, output, age } => {
+            let output = Bombadil::from_settings(Mode::Gpg)
+                .and_then(|bombadil| bombadil.encrypt_file(&file, output.as_deref(), age))
+                .unwrap_or_else(|err| fatal!("{}", err));
+            println!("Encrypted {:?} to {:?}", file, output);
+        }
+        Cli::Secret { command } => {
+            let bombadil =
+                Bombadil::from_settings(Mode::Gpg).unwrap_or_else(|err| fatal!("{}", err));
+
+            match command {
+                SecretCommand::List { file } => {
+                    bombadil.list_secrets(file.as_deref(), &mut io::stdout())
+                }
+                SecretCommand::Recipients { file } => {
+                    bombadil.secret_recipients(file.as_deref(), &mut io::stdout())
+                }
+                SecretCommand::Remove { key, file } => bombadil.remove_secret(&key, &file),
+                SecretCommand::Edit { key, file } => bombadil.edit_secret(&key, &file),
+                SecretCommand::Reencrypt { recipients, file } => {
+                    bombadil.reencrypt_secrets(&recipients, file.as_deref())
+                }
+            }
+            .unwrap_or_else(|err| fatal!("{}", err));
+        }
         Cli::Get {
             value,
             profiles,
             no_color,
+            reveal,
         } => {
             let metadata_type = match value.as_str() {
                 "dots" => MetadataType::Dots,
@@ -213,9 +400,14 @@ async fn main() -> Result<()> {
                 .enable_profiles(profiles.iter().map(String::as_str).collect())
                 .unwrap_or_else(|err| fatal!("{}", err));
 
-            bombadil
-                .print_metadata(metadata_type, &mut io::stdout(), no_color)
-                .expect("Failed to write metadata to stdout");
+            match metadata_type {
+                MetadataType::Secrets => bombadil
+                    .print_secrets(reveal, &mut io::stdout(), no_color)
+                    .unwrap_or_else(|err| fatal!("{}", err)),
+                _ => bombadil
+                    .print_metadata(metadata_type, &mut io::stdout(), no_color)
+                    .expect("Failed to write metadata to stdout"),
+            }
         }
         Cli::GenerateCompletions { shell } => {
             clap_complete::generate(shell, &mut Cli::command(), "bombadil", &mut io::stdout())
@@ -0,0 +1,414 @@
+use crate::dots::DotVar;
+use crate::paths;
+use crate::paths::DotPaths;
+use crate::secrets;
+use crate::settings::imports::ImportedSettings;
+use crate::settings::Settings;
+use crate::templating::Variables;
+use crate::{Bombadil, Mode, SECRET_MASK};
+use config::{Config, File};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use tera::Value;
+
+/// A configuration problem found by `bombadil check`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// What the problem is about: the settings, an import, a var file, a profile or a dot
+    pub subject: String,
+    pub message: String,
+    /// Profiles enabled when the problem was found, empty for the default profile
+    pub profiles: Vec<String>,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.subject)?;
+        if !self.profiles.is_empty() {
+            write!(f, " (profiles: {})", self.profiles.join(", "))?;
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+// Problems are reported once, with the first profiles they were found with
+#[derive(Default)]
+struct Problems(BTreeMap<(String, String), Vec<String>>);
+
+impl Problems {
+    fn push(&mut self, profiles: &[String], subject: impl Into<String>, message: impl ToString) {
+        self.0
+            .entry((subject.into(), message.to_string()))
+            .or_insert_with(|| profiles.to_vec());
+    }
+
+    // Broken var files also fail loading the settings, they are only reported once
+    fn is_reported(&self, message: &str) -> bool {
+        self.0.keys().any(|(_, reported)| reported == message)
+    }
+
+    fn into_vec(self) -> Vec<Problem> {
+        self.0
+            .into_iter()
+            .map(|((subject, message), profiles)| Problem {
+                subject,
+                message,
+                profiles,
+            })
+            .collect()
+    }
+}
+
+impl Bombadil {
+    /// Check the settings, their imports and every file they reference without linking anything.
+    /// Dots are checked with the default profile, every combination of profiles and the profiles
+    /// of each `hosts` entry. With more than 8 profiles, each profile is only checked on its own.
+    /// Secrets are replaced by a placeholder unless `decrypt` is set.
+    pub fn check(decrypt: bool) -> Vec<Problem> {
+        let mut problems = Problems::default();
+        let settings = match Settings::get() {
+            Ok(settings) => settings,
+            Err(err) => {
+                problems.push(&[], "settings", err);
+                return problems.into_vec();
+            }
+        };
+
+        check_imports(&settings, &mut problems);
+        check_var_files(&settings, &mut problems);
+        check_profiles(&settings, &mut problems);
+
+        for profiles in profile_combinations(&settings) {
+            let mode = if decrypt { Mode::Gpg } else { Mode::NoGpg };
+            let bombadil = Bombadil::from_settings(mode).and_then(|mut bombadil| {
+                bombadil.enable_profiles(profiles.iter().map(String::as_str).collect())?;
+                Ok(bombadil)
+            });
+
+            match bombadil {
+                Ok(bombadil) => bombadil.check_dots(decrypt, &profiles, &mut problems),
+                Err(err) if problems.is_reported(&err.to_string()) => {}
+                Err(err) => problems.push(&profiles, "settings", err),
+            }
+        }
+
+        problems.into_vec()
+    }
+
+    // Check dot sources, var files, templates and targets of the enabled profiles
+    fn check_dots(&self, decrypt: bool, profiles: &[String], problems: &mut Problems) {
+        let vars = match self.template_vars(decrypt) {
+            Ok(vars) => vars,
+            Err(err) => {
+                problems.push(profiles, "secrets", err);
+                return;
+            }
+        };
+
+        let mut dots: Vec<_> = self.dots.iter().collect();
+        dots.sort_by_key(|(key, _)| *key);
+
+        for (key, dot) in &dots {
+            let subject = format!("dot `{key}`");
+
+            // External repositories are only available once cloned by `bombadil link`
+            if dot
+                .external_repository()
+                .is_some_and(|repository| !repository.exists())
+            {
+                let message = "external repository not cloned, run `bombadil link`";
+                problems.push(profiles, subject, message);
+                continue;
+            }
+
+            let source = match dot.source() {
+                Ok(source) => source,
+                Err(err) => {
+                    problems.push(profiles, subject, err);
+                    continue;
+                }
+            };
+
+            // Broken local var files are only a warning when linking, rendering the dot
+            // without them would report every variable they define as missing
+            match dot.find_var_path(&dot.source_root(), &dot.source, &dot.vars) {
+                Some(path) => {
+                    if let Err(err) = Variables::from_path(&path) {
+                        problems.push(profiles, &subject, err);
+                        continue;
+                    }
+                }
+                None if !dot.is_default_var_path() => {
+                    let message = format!("var file {:?} not found", dot.vars);
+                    problems.push(profiles, &subject, message);
+                }
+                None => {}
+            }
+
+            if dot.direct || secrets::is_encrypted_file(&source) {
+                continue;
+            }
+
+            let render_errors = dot
+                .with_local_vars(&vars, decrypt)
+                .map(|mut vars| {
+                    if !decrypt {
+                        mask_secrets(&mut vars);
+                    }
+
+                    vars
+                })
+                .and_then(|vars| Ok(vars.to_context()?))
+                .and_then(|context| dot.render_errors(&context, &self.get_auto_ignored_files(key)));
+
+            match render_errors {
+                Ok(errors) => {
+                    for (_, error) in errors {
+                        problems.push(profiles, &subject, error);
+                    }
+                }
+                Err(err) => problems.push(profiles, &subject, err),
+            }
+        }
+
+        for collision in paths::target_collisions(&self.dots) {
+            let (key, _) = collision.inner;
+            problems.push(profiles, format!("dot `{key}`"), collision);
+        }
+    }
+}
+
+fn check_imports(settings: &Settings, problems: &mut Problems) {
+    for path in settings.import_paths() {
+        let subject = format!("import {path:?}");
+        if !path.exists() {
+            problems.push(&[], subject, "file not found");
+            continue;
+        }
+
+        let imported = Config::builder()
+            .add_source(File::from(path.as_path()))
+            .build()
+            .and_then(|config| config.try_deserialize::<ImportedSettings>());
+
+        if let Err(err) = imported {
+            problems.push(&[], subject, err);
+        }
+    }
+}
+
+fn check_var_files(settings: &Settings, problems: &mut Problems) {
+    let Ok(dotfiles_path) = settings.get_dotfiles_path() else {
+        // Reported when loading the dots
+        return;
+    };
+
+    let mut var_paths: Vec<&PathBuf> = settings
+        .settings
+        .vars
+        .iter()
+        .chain(settings.profiles.values().flat_map(|profile| &profile.vars))
+        .collect();
+    var_paths.sort();
+    var_paths.dedup();
+
+    for path in var_paths {
+        let path = shellexpand::tilde(path.to_string_lossy().as_ref()).to_string();
+        let path = dotfiles_path.join(path);
+        let subject = format!("var file {path:?}");
+        if !path.exists() {
+            problems.push(&[], subject, "file not found");
+        } else if let Err(err) = Variables::from_path(&path) {
+            problems.push(&[], subject, err);
+        }
+    }
+}
+
+fn check_profiles(settings: &Settings, problems: &mut Problems) {
+    let mut profiles: Vec<_> = settings.profiles.iter().collect();
+    profiles.sort_by_key(|(name, _)| *name);
+
+    for (name, profile) in profiles {
+        let subject = format!("profile `{name}`");
+        for extra in &profile.extra_profiles {
+            if !settings.profiles.contains_key(extra) {
+                problems.push(&[], &subject, format!("unknown extra profile `{extra}`"));
+            }
+        }
+
+        let mut dots: Vec<_> = profile.dots.iter().collect();
+        dots.sort_by_key(|(key, _)| *key);
+
+        for (key, dot) in dots {
+            let overridden = settings.settings.dots.contains_key(key);
+            let has_source = dot.source.is_some() || dot.git.is_some();
+            if overridden
+                && !has_source
+                && dot.target.is_none()
+                && dot.vars.is_none()
+                && dot.direct.is_none()
+                && dot.posthooks.is_none()
+                && dot.systemd.is_none()
+                && dot.rev.is_none()
+            {
+                problems.push(&[], &subject, format!("dot `{key}` overrides nothing"));
+            } else if !overridden && (!has_source || dot.target.is_none()) {
+                let message = format!("new dot `{key}` needs both a `source` and a `target`");
+                problems.push(&[], &subject, message);
+            }
+        }
+    }
+
+    let mut hosts: Vec<_> = settings.hosts.iter().collect();
+    hosts.sort();
+
+    for (host, profiles) in hosts {
+        for profile in profiles {
+            if !settings.profiles.contains_key(profile) {
+                let message = format!("unknown profile `{profile}`");
+                problems.push(&[], format!("host `{host}`"), message);
+            }
+        }
+    }
+}
+
+// Above this number of profiles, each profile is checked on its own instead of every combination
+const MAX_COMBINED_PROFILES: usize = 8;
+
+// The default profile and every combination of profiles, or each profile on its own when there
+// are too many of them, followed by the profiles of each host
+fn profile_combinations(settings: &Settings) -> Vec<Vec<String>> {
+    let mut names: Vec<&String> = settings.profiles.keys().collect();
+    names.sort();
+
+    let mut combinations: Vec<Vec<String>> = if names.len() <= MAX_COMBINED_PROFILES {
+        (0..1usize << names.len())
+            .map(|mask| {
+                names
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| mask & (1 << index) != 0)
+                    .map(|(_, name)| (*name).clone())
+                    .collect()
+            })
+            .collect()
+    } else {
+        let singles = names.into_iter().map(|name| vec![name.clone()]);
+        std::iter::once(vec![]).chain(singles).collect()
+    };
+
+    // Smaller combinations first, problems are reported with the first profiles they appear with
+    combinations.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+
+    let mut hosts: Vec<Vec<String>> = settings
+        .hosts
+        .values()
+        .map(|profiles| {
+            profiles
+                .iter()
+                .filter(|profile| settings.profiles.contains_key(*profile))
+                .cloned()
+                .collect()
+        })
+        .collect();
+    hosts.sort();
+
+    for profiles in hosts {
+        if !combinations.contains(&profiles) {
+            combinations.push(profiles);
+        }
+    }
+
+    combinations
+}
+
+// Secrets are not decrypted, templates get a placeholder instead
+fn mask_secrets(vars: &mut Variables) {
+    let Some(secrets) = vars.inner.get_mut("secrets").and_then(Value::as_object_mut) else {
+        return;
+    };
+
+    secrets.retain(|key, _| !key.starts_with(secrets::BLOB_KEY));
+    for value in secrets.values_mut() {
+        *value = Value::String(SECRET_MASK.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_helpers::setup;
+    use crate::Bombadil;
+    use anyhow::Result;
+    use indoc::indoc;
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::fs;
+
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn should_not_report_valid_settings() {
+        // Act
+        let problems = Bombadil::check(false);
+
+        // Assert
+        assert_that!(problems).is_empty();
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn should_report_external_dots_not_cloned() -> Result<()> {
+        // Arrange
+        fs::write(
+            "dotfiles_simple/bombadil.toml",
+            indoc! {r#"
+                dotfiles_dir = "dotfiles_simple"
+
+                [settings.dots]
+                theme = { git = "https://example.org/theme.git", source = "theme", target = ".config/theme" }
+            "#},
+        )?;
+
+        // Act
+        let problems: Vec<String> = Bombadil::check(false)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        // Assert
+        assert_that!(problems).is_equal_to(vec![
+            "dot `theme`: external repository not cloned, run `bombadil link`".to_string(),
+        ]);
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_problems"], before = setup("dotfiles_with_problems"))]
+    fn should_report_every_problem() {
+        // Act
+        let problems: Vec<String> = Bombadil::check(false)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        // Assert
+        let expected = [
+            "missing.toml\": file not found",
+            "broken.toml\": parse error",
+            "profile `work`: unknown extra profile `unknown`",
+            "profile `work`: dot `css` overrides nothing",
+            "profile `work`: new dot `new_dot` needs both a `source` and a `target`",
+            "host `laptop`: unknown profile `ghost`",
+            "dot `broken`: ",
+            "dot `missing`: ",
+            "dot `local_vars`: var file \"nope.toml\" not found",
+            "dot `local_broken`: parse error in ",
+            "dot `right` (profiles: left, right): dots `left` (profile `left`) and `right` (profile `right`) are both linked to",
+            "dot `css` (profiles: colliding): dots `broken` (profile `colliding`) and `css` (bombadil.toml) are both linked to",
+        ];
+
+        for expected in expected {
+            assert_that!(problems).matching_contains(|problem| problem.contains(expected));
+        }
+
+        assert_that!(problems).has_length(expected.len());
+    }
+}
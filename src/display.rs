@@ -4,9 +4,22 @@ pub mod links {
     use colored::Colorize;
     use std::fmt::Formatter;
     use std::io::Write;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use std::{fmt, io};
 
+    impl LinkResult {
+        /// The path used to order link results in the install summary
+        fn display_path(&self) -> &Path {
+            match self {
+                LinkResult::Updated { target, .. }
+                | LinkResult::Created { target, .. }
+                | LinkResult::Direct { target, .. }
+                | LinkResult::Unchanged { target } => target,
+                LinkResult::Ignored { source } => source,
+            }
+        }
+    }
+
     impl fmt::Display for LinkResult {
         fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
             match self {
@@ -21,8 +34,14 @@ pub mod links {
         }
     }
 
-    pub fn write(results: Vec<LinkResult>, out: &mut impl Write, title: &str) -> io::Result<()> {
+    pub fn write(
+        mut results: Vec<LinkResult>,
+        out: &mut impl Write,
+        title: &str,
+    ) -> io::Result<()> {
         if !results.is_empty() {
+            // Dots are rendered in parallel, sort them to keep a stable output
+            results.sort_by(|a, b| a.display_path().cmp(b.display_path()));
             writeln!(out, "{}", format!("[{title}]").bold().yellow())?;
             for result in results {
                 write!(out, "{}", result)?;
@@ -32,8 +51,12 @@ pub mod links {
         Ok(())
     }
 
-    pub fn write_errors(errored: Vec<(PathBuf, Error)>, out: &mut impl Write) -> io::Result<()> {
+    pub fn write_errors(
+        mut errored: Vec<(PathBuf, Error)>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
         if !errored.is_empty() {
+            errored.sort_by(|(a, _), (b, _)| a.cmp(b));
             writeln!(out, "{}", "[Errored]".bold().red())?;
             for (path, error) in errored {
                 writeln!(out, "{path:?}: {error:?}")?;
@@ -43,8 +66,9 @@ pub mod links {
         Ok(())
     }
 
-    pub fn write_deletion(deleted: Vec<String>, out: &mut impl Write) -> io::Result<()> {
+    pub fn write_deletion(mut deleted: Vec<String>, out: &mut impl Write) -> io::Result<()> {
         if !deleted.is_empty() {
+            deleted.sort();
             writeln!(out, "{}", "[Deleted]".bold().red())?;
             for deleted in deleted {
                 writeln!(out, "{deleted:?}")?;
@@ -54,3 +78,33 @@ pub mod links {
         Ok(())
     }
 }
+
+pub mod hooks {
+    use crate::hook::HookResult;
+    use colored::Colorize;
+    use std::io;
+    use std::io::Write;
+
+    pub(crate) fn write(results: &[HookResult], out: &mut impl Write) -> io::Result<()> {
+        if !results.is_empty() {
+            writeln!(out, "{}", "[Hooks]".bold().yellow())?;
+            for result in results {
+                let status = if result.success() {
+                    result.status.to_string().green()
+                } else {
+                    result.status.to_string().red()
+                };
+
+                writeln!(
+                    out,
+                    "{:?}: {} ({:.2}s)",
+                    result.name,
+                    status,
+                    result.duration.as_secs_f64()
+                )?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
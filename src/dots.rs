@@ -1,15 +1,24 @@
+use crate::paths;
 use crate::paths::DotPaths;
+use crate::secrets;
 use crate::settings::dotfile_dir;
 use crate::settings::dots::{Dot, DotOverride};
-use crate::templating::Variables;
+use crate::templating::{render, Variables};
 use anyhow::Result;
 use colored::*;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
 use std::error::Error;
 use std::fs;
-use std::fs::{File, OpenOptions};
+use std::fs::{File, OpenOptions, Permissions};
 use std::io::Write;
+use std::os::unix;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use tera::ErrorKind;
+use std::sync::Mutex;
+use tera::{Context, ErrorKind};
 
 #[derive(PartialEq, Eq, Debug)]
 pub enum LinkResult {
@@ -20,14 +29,67 @@ pub enum LinkResult {
     Unchanged { target: PathBuf },
 }
 
+/// Rendered files holding decrypted secrets, collected while dots are rendered
+#[derive(Debug, Default)]
+pub(crate) struct SecretRenders {
+    /// Write them to this directory instead of `.dots`, their `.dots` copy links to it
+    runtime_dir: Option<PathBuf>,
+    /// Identity decrypting `.age` dot sources
+    age_identity: Option<PathBuf>,
+    paths: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl SecretRenders {
+    pub(crate) fn new(runtime_dir: Option<PathBuf>, age_identity: Option<PathBuf>) -> Self {
+        Self {
+            runtime_dir,
+            age_identity,
+            paths: Mutex::default(),
+        }
+    }
+
+    /// Paths of the files written with decrypted secrets
+    pub(crate) fn into_paths(self) -> BTreeSet<PathBuf> {
+        self.paths
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    // Where the secrets of a `.dots` copy are written
+    fn location(&self, copy: &Path) -> PathBuf {
+        self.runtime_dir
+            .as_ref()
+            .and_then(|runtime_dir| {
+                copy.strip_prefix(dotfile_dir().join(".dots"))
+                    .ok()
+                    .map(|relative| runtime_dir.join(relative))
+            })
+            .unwrap_or_else(|| copy.to_path_buf())
+    }
+
+    fn push(&self, path: PathBuf) {
+        self.paths
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(path);
+    }
+}
+
 impl Dot {
     pub(crate) fn install(
         &self,
         vars: &Variables,
         auto_ignored: Vec<PathBuf>,
+        secrets: &SecretRenders,
     ) -> Result<LinkResult> {
         let source = &self.source()?;
         let target = &self.copy_path_unchecked();
+
+        // Encrypted files are decrypted to the copy, never linked directly
+        if secrets::is_encrypted_file(source) {
+            return self.decrypt_file(source, target, secrets);
+        }
+
         let source_str = source.to_str().unwrap_or_default();
 
         let ignored_paths = if self.ignore.is_empty() {
@@ -38,12 +100,55 @@ impl Dot {
             ignored_paths
         };
 
-        // Add local vars to the global ones
+        // Build the template context once, it is shared by every file in the dot
+        let context = if self.direct {
+            None
+        } else {
+            Some(self.with_local_vars(vars, true)?.to_context()?)
+        };
+
+        // Recursively copy dotfile to the.dots directory
+        self.traverse_and_copy(
+            source,
+            target,
+            ignored_paths.as_slice(),
+            context.as_ref(),
+            secrets,
+        )
+    }
+
+    // Decrypting prompts for a passphrase, this is skipped when the copy was decrypted from
+    // the same ciphertext, whose hash is stored next to it
+    fn decrypt_file(
+        &self,
+        source: &Path,
+        target: &Path,
+        secrets: &SecretRenders,
+    ) -> Result<LinkResult> {
+        let location = secrets.location(target);
+        let hash_path = ciphertext_hash_path(&location);
+        let hash = ciphertext_hash(source, secrets.age_identity.as_deref())?;
+        if target.exists() && fs::read_to_string(&hash_path).is_ok_and(|stored| stored == hash) {
+            secrets.push(location);
+            return Ok(LinkResult::Unchanged {
+                target: self.target()?,
+            });
+        }
+
+        let content = secrets::decrypt_file(source, secrets.age_identity.as_deref())?;
+        fs::create_dir_all(target.parent().unwrap())?;
+        let linked = self.write_secret(target, content.as_slice(), secrets)?;
+        fs::write(&hash_path, hash)?;
+        Ok(linked)
+    }
+
+    /// Add the dot local vars to the global ones, optionally decrypting their secrets
+    pub(crate) fn with_local_vars(&self, vars: &Variables, decrypt: bool) -> Result<Variables> {
         let mut vars = vars.clone();
 
         if let Some(local_vars_path) = self.resolve_var_path() {
             let mut local_vars = Dot::load_local_vars(&local_vars_path);
-            if local_vars.has_secrets() {
+            if decrypt && local_vars.has_secrets() {
                 let decrypted = local_vars.get_secrets()?;
                 local_vars.with_secrets(decrypted);
             }
@@ -51,10 +156,50 @@ impl Dot {
             vars.extend(local_vars);
         }
 
-        let vars = (!self.direct).then_some(vars);
+        Ok(vars)
+    }
 
-        // Recursively copy dotfile to the.dots directory
-        self.traverse_and_copy(source, target, ignored_paths.as_slice(), vars.as_ref())
+    /// Render every file of the dot without writing them, returning the ones failing to render
+    pub(crate) fn render_errors(
+        &self,
+        context: &Context,
+        auto_ignored: &[PathBuf],
+    ) -> Result<Vec<(PathBuf, String)>> {
+        let source = self.source()?;
+        let mut ignored = self.get_ignored_paths(source.to_str().unwrap_or_default())?;
+        ignored.extend_from_slice(auto_ignored);
+
+        let mut errors = vec![];
+        let mut sources = vec![source];
+        while let Some(source) = sources.pop() {
+            if ignored.contains(&source) {
+                continue;
+            }
+
+            if source.is_dir() {
+                for entry in source.read_dir()? {
+                    sources.push(entry?.path());
+                }
+            } else if let Err(err) = render(&source, context) {
+                // Non utf8 files are copied as is
+                if !matches!(
+                    err.kind,
+                    ErrorKind::Utf8Conversion { .. } | ErrorKind::Io(..)
+                ) {
+                    let mut message = err.to_string();
+                    let mut cause = err.source();
+                    while let Some(err) = cause {
+                        message.push_str(&format!(": {err}"));
+                        cause = err.source();
+                    }
+
+                    errors.push((source, message));
+                }
+            }
+        }
+
+        errors.sort();
+        Ok(errors)
     }
 
     fn load_local_vars(source: &Path) -> Variables {
@@ -79,7 +224,8 @@ impl Dot {
         source: &PathBuf,
         target: &PathBuf,
         ignored: &[PathBuf],
-        vars: Option<&Variables>,
+        context: Option<&Context>,
+        secrets: &SecretRenders,
     ) -> Result<LinkResult> {
         if ignored.contains(source) {
             return Ok(LinkResult::Ignored {
@@ -87,9 +233,9 @@ impl Dot {
             });
         }
 
-        match vars {
-            Some(vars) if source.is_file() => self.render_file(source, target, vars),
-            Some(vars) => self.render_directory(source, target, ignored, vars),
+        match context {
+            Some(context) if source.is_file() => self.render_file(source, target, context, secrets),
+            Some(context) => self.render_directory(source, target, ignored, context, secrets),
             None => Ok(LinkResult::Direct {
                 source: source.clone(),
                 target: self.target()?,
@@ -102,25 +248,35 @@ impl Dot {
         source: &Path,
         target: &PathBuf,
         ignored: &[PathBuf],
-        vars: &Variables,
+        context: &Context,
+        secrets: &SecretRenders,
     ) -> std::result::Result<LinkResult, anyhow::Error> {
         fs::create_dir_all(target)?;
-        let mut link_results = vec![];
-        for entry in source.read_dir()? {
-            let entry_path = &entry?.path();
-            let entry_name = entry_path.file_name().unwrap().to_str().unwrap();
-            let result = self.traverse_and_copy(
-                &source.join(entry_name),
-                &target.join(entry_name),
-                ignored,
-                Some(vars),
-            );
-
-            match result {
-                Ok(result) => link_results.push(result),
-                Err(err) => eprintln!("{err}"),
-            }
-        }
+        let mut entries = source
+            .read_dir()?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        // Sort entries so errors are always reported in the same order
+        entries.sort();
+
+        // Entries are rendered on the rayon thread pool, nested directories
+        // are split into smaller tasks by the same pool
+        let link_results: Vec<LinkResult> = entries
+            .par_iter()
+            .map(|entry_name| {
+                self.traverse_and_copy(
+                    &source.join(entry_name),
+                    &target.join(entry_name),
+                    ignored,
+                    Some(context),
+                    secrets,
+                )
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .filter_map(|result| result.map_err(|err| eprintln!("{err}")).ok())
+            .collect();
 
         if link_results
             .iter()
@@ -149,12 +305,23 @@ impl Dot {
         &self,
         source: &PathBuf,
         target: &PathBuf,
-        vars: &Variables,
+        context: &Context,
+        secrets: &SecretRenders,
     ) -> std::result::Result<LinkResult, anyhow::Error> {
         fs::create_dir_all(target.parent().unwrap())?;
-        match vars.to_dot(source) {
-            Ok(content) if target.exists() => self.update(source, target, content),
-            Ok(content) => self.create(source, target, content),
+        let rendered = render(source, context);
+
+        // Only copies holding secrets are links to the runtime directory
+        if target.is_symlink() && !rendered.as_ref().is_ok_and(|rendered| rendered.has_secrets) {
+            fs::remove_file(target)?;
+        }
+
+        match rendered {
+            Ok(rendered) if rendered.has_secrets => {
+                self.write_secret(target, rendered.content.as_bytes(), secrets)
+            }
+            Ok(rendered) if target.exists() => self.update(source, target, rendered.content),
+            Ok(rendered) => self.create(source, target, rendered.content),
             Err(e) if target.exists() => {
                 match e.kind {
                     ErrorKind::Utf8Conversion { .. } | ErrorKind::Io(..) => {
@@ -181,6 +348,69 @@ impl Dot {
         }
     }
 
+    // Write a render holding secrets, readable by its owner only
+    fn write_secret(
+        &self,
+        target: &Path,
+        content: &[u8],
+        secrets: &SecretRenders,
+    ) -> Result<LinkResult> {
+        let path = secrets.location(target);
+
+        // A link left by a previous render to another location
+        if target.is_symlink() && fs::read_link(target)? != path {
+            fs::remove_file(target)?;
+        }
+
+        let existed = target.exists();
+        let unchanged = existed && fs::read(&path).is_ok_and(|old| old == content);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(!unchanged)
+            .mode(0o600)
+            .open(&path)?;
+
+        // The mode is only applied to new files
+        file.set_permissions(Permissions::from_mode(0o600))?;
+
+        if !unchanged {
+            file.write_all(content)?;
+            file.sync_data()?;
+        }
+
+        if path != target && !target.is_symlink() {
+            if target.exists() {
+                fs::remove_file(target)?;
+            }
+
+            unix::fs::symlink(&path, target)?;
+        }
+
+        secrets.push(path);
+
+        if unchanged {
+            Ok(LinkResult::Unchanged {
+                target: self.target()?,
+            })
+        } else if existed {
+            Ok(LinkResult::Updated {
+                target: self.target()?,
+                copy: self.copy_path()?,
+            })
+        } else {
+            Ok(LinkResult::Created {
+                target: self.target()?,
+                copy: self.copy_path()?,
+            })
+        }
+    }
+
     fn create(&self, source: &PathBuf, target: &PathBuf, content: String) -> Result<LinkResult> {
         let permissions = fs::metadata(source)?.permissions();
         let mut dot_copy = File::create(target)?;
@@ -235,20 +465,50 @@ impl Dot {
 }
 
 impl DotOverride {
-    pub(crate) fn resolve_var_path(&self, origin: Option<&PathBuf>) -> Option<PathBuf> {
+    pub(crate) fn resolve_var_path(&self, origin: Option<&Dot>) -> Option<PathBuf> {
         let source = match (self.get_source(), origin) {
             (Some(source), _) => source,
-            (None, Some(origin)) => origin,
+            (None, Some(origin)) => &origin.source,
             _ => panic!("Dot has no source path"),
         };
 
+        // The source of an override is taken from the external repository of its origin,
+        // unless the override points to another one
+        let root = match (&self.git, origin) {
+            (Some(url), _) => paths::external_repository(url),
+            (None, Some(origin)) => origin.source_root(),
+            (None, None) => dotfile_dir(),
+        };
+
         let vars = self.vars().unwrap_or_else(Dot::default_vars);
-        self.resolve_from_source(source, &vars)
+        self.resolve_from_source(&root, source, &vars)
     }
 }
 
 impl Dot {}
 
+/// Where the hash of the ciphertext a file was decrypted from is stored
+pub(crate) fn ciphertext_hash_path(decrypted: &Path) -> PathBuf {
+    let mut path = decrypted.as_os_str().to_owned();
+    path.push(".sha256");
+    PathBuf::from(path)
+}
+
+// Hex encoded sha256 of an encrypted file along with the age identity decrypting it
+fn ciphertext_hash(source: &Path, age_identity: Option<&Path>) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(fs::read(source)?);
+    if let Some(identity) = age_identity {
+        hasher.update(identity.as_os_str().as_bytes());
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
 pub(crate) trait DotVar {
     fn vars(&self) -> Option<PathBuf>;
 
@@ -262,8 +522,15 @@ pub(crate) trait DotVar {
         self.vars() == Some(Dot::default_vars())
     }
 
-    fn resolve_from_source(&self, source: &Path, path: &Path) -> Option<PathBuf> {
-        let relative_to_dot = dotfile_dir().join(source).join(path);
+    fn resolve_from_source(&self, root: &Path, source: &Path, path: &Path) -> Option<PathBuf> {
+        self.find_var_path(root, source, path)
+            .or_else(|| self.vars_path_not_found(source, path))
+    }
+
+    /// Look for a var path next to the dot source, `root` being the directory `source`
+    /// is relative to, then in the dotfiles directory
+    fn find_var_path(&self, root: &Path, source: &Path, path: &Path) -> Option<PathBuf> {
+        let relative_to_dot = root.join(source).join(path);
         let relative_to_dotfile_dir = dotfile_dir().join(path);
         // FIXME : we should not try to look for path like this
         // Instead "../vars.toml" should be used
@@ -275,14 +542,14 @@ pub(crate) trait DotVar {
             } else if relative_to_dotfile_dir.exists() && !self.is_default_var_path() {
                 Some(relative_to_dotfile_dir)
             } else {
-                self.vars_path_not_found(source, path)
+                None
             }
         } else {
-            // Warning is emitted only if the path is not "vars.toml"
-            self.vars_path_not_found(source, path)
+            None
         }
     }
 
+    // Warning is emitted only if the path is not "vars.toml"
     fn vars_path_not_found(&self, source: &Path, path: &Path) -> Option<PathBuf> {
         if !self.is_default_var_path() {
             eprintln!(
@@ -321,9 +588,10 @@ impl DotVar for DotOverride {
 
 #[cfg(test)]
 mod tests {
-    use crate::dots::DotVar;
+    use crate::dots::{DotVar, SecretRenders};
     use crate::settings::dots::Dot;
     use crate::templating::Variables;
+    use crate::test_helpers::setup;
     use crate::Mode::NoGpg;
     use crate::{Bombadil, DotPaths};
     use anyhow::Result;
@@ -333,16 +601,6 @@ mod tests {
     use std::path::PathBuf;
     use std::{env, fs};
 
-    fn setup(dotfiles: &str) {
-        env::set_var("HOME", env::current_dir().unwrap());
-        #[cfg(target_os = "macos")]
-        run_cmd!(mkdir -p "Library/Application Support";).unwrap();
-        println!("Fake home: {}", env::var("HOME").unwrap());
-        run_cmd!(mkdir .config;).unwrap();
-
-        Bombadil::link_self_config(Some(PathBuf::from(dotfiles))).unwrap();
-    }
-
     #[sealed_test]
     fn should_get_target_path() {
         // Arrange
@@ -354,6 +612,7 @@ mod tests {
             ignore: vec![],
             vars: Dot::default_vars(),
             direct: false,
+            ..Default::default()
         };
 
         // Act
@@ -374,6 +633,7 @@ mod tests {
             ignore: vec![],
             vars: Dot::default_vars(),
             direct: false,
+            ..Default::default()
         };
 
         // Act
@@ -399,6 +659,7 @@ mod tests {
             ignore: vec![],
             vars: Dot::default_vars(),
             direct: false,
+            ..Default::default()
         };
 
         // Act
@@ -421,6 +682,7 @@ mod tests {
             ignore: vec![],
             vars: Dot::default_vars(),
             direct: false,
+            ..Default::default()
         };
 
         // Act
@@ -428,7 +690,8 @@ mod tests {
             &PathBuf::from("dotfiles_with_multiple_nested_dir/dir"),
             &PathBuf::from("dotfiles_with_multiple_nested_dir/.dots/dir"),
             &[],
-            Some(&Variables::default()),
+            Some(&Variables::default().to_context()?),
+            &SecretRenders::default(),
         )?;
 
         // Assert
@@ -454,6 +717,7 @@ mod tests {
             ignore: vec![],
             vars: Dot::default_vars(),
             direct: false,
+            ..Default::default()
         };
 
         run_cmd! {ls -larth;}?;
@@ -462,7 +726,8 @@ mod tests {
             &source,
             &PathBuf::from("dotfiles_non_utf8/.dots/ferris.png"),
             &[],
-            Some(&Variables::default()),
+            Some(&Variables::default().to_context()?),
+            &SecretRenders::default(),
         )?;
 
         assert_that!(PathBuf::from("dotfiles_non_utf8/.dots/ferris.png")).exists();
@@ -490,6 +755,7 @@ mod tests {
             ignore: vec!["*.md".to_string()],
             vars: Dot::default_vars(),
             direct: false,
+            ..Default::default()
         };
 
         // Act
@@ -500,7 +766,8 @@ mod tests {
                 PathBuf::from("source_dot/subdir_two/subfile.md"),
                 PathBuf::from("source_dot/file.md"),
             ],
-            Some(&Variables::default()),
+            Some(&Variables::default().to_context()?),
+            &SecretRenders::default(),
         )?;
 
         // Assert
@@ -534,6 +801,7 @@ mod tests {
             ignore: vec![],
             vars: Dot::default_vars(),
             direct: false,
+            ..Default::default()
         };
 
         dot.symlink(false)?;
@@ -562,9 +830,10 @@ mod tests {
             ignore: vec![],
             vars: Dot::default_vars(),
             direct: false,
+            ..Default::default()
         };
 
-        dot.install(&Variables::default(), vec![])?;
+        dot.install(&Variables::default(), vec![], &SecretRenders::default())?;
 
         assert_that!(PathBuf::from(".dots")).exists();
         assert_that!(PathBuf::from(".dots/source_dot")).exists();
@@ -584,12 +853,13 @@ mod tests {
             ignore: vec![],
             vars: Dot::default_vars(),
             direct: false,
+            ..Default::default()
         };
 
         let vars: Variables = toml::from_str(r#"name = "Tom Bombadil""#)?;
 
         // Act
-        dot.install(&vars, vec![])?;
+        dot.install(&vars, vec![], &SecretRenders::default())?;
         let dot = PathBuf::from(".dots/dotfiles/dot");
 
         // Assert
@@ -626,9 +896,10 @@ mod tests {
             ignore: vec![],
             vars: PathBuf::from("my_vars.toml"),
             direct: false,
+            ..Default::default()
         };
 
-        dot.install(&Variables::default(), vec![])?;
+        dot.install(&Variables::default(), vec![], &SecretRenders::default())?;
 
         let content = fs::read_to_string(".dots/dir/template")?;
         assert_that!(content).is_equal_to(&"Hello Tom\n".to_string());
@@ -656,10 +927,11 @@ mod tests {
             // FIXME: this should be relative to the dotfile directory
             vars: PathBuf::from("dotfiles_with_local_vars/source_dot/vars.toml"),
             direct: false,
+            ..Default::default()
         };
 
         // Arrange
-        dot.install(&Variables::default(), vec![])?;
+        dot.install(&Variables::default(), vec![], &SecretRenders::default())?;
 
         // Assert
         let content = fs::read_to_string(PathBuf::from(
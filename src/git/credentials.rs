@@ -0,0 +1,396 @@
+use crate::settings::git::GitSettings;
+use git2::{Config, Cred, CredentialType};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_SSH_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+pub(crate) const USERNAME_ENV: &str = "BOMBADIL_GIT_USERNAME";
+pub(crate) const PASSWORD_ENV: &str = "BOMBADIL_GIT_PASSWORD";
+
+/// Provide credentials to libgit2, which calls back until authentication succeeds.
+/// Each call tries the next available method, the ones already attempted are reported on failure:
+/// - ssh: ssh-agent, `IdentityFile` entries from `~/.ssh/config` (if enabled), then default keys
+/// - https: git credential helpers, then `BOMBADIL_GIT_USERNAME` and `BOMBADIL_GIT_PASSWORD`
+pub(crate) struct CredentialHandler {
+    settings: GitSettings,
+    config: Option<Config>,
+    ssh_methods: Option<Vec<SshMethod>>,
+    tried_helper: bool,
+    tried_env: bool,
+    tried_default: bool,
+    attempted: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SshMethod {
+    Agent,
+    Key(PathBuf),
+}
+
+impl CredentialHandler {
+    /// `config` is the git configuration used to look up credential helpers
+    pub(crate) fn new(settings: &GitSettings, config: Option<Config>) -> Self {
+        Self {
+            settings: settings.clone(),
+            config,
+            ssh_methods: None,
+            tried_helper: false,
+            tried_env: false,
+            tried_default: false,
+            attempted: vec![],
+        }
+    }
+
+    pub(crate) fn credentials(
+        &mut self,
+        url: &str,
+        user_from_url: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        if allowed.contains(CredentialType::USERNAME) {
+            let user = self
+                .username(user_from_url)
+                .unwrap_or_else(|| "git".to_string());
+            return Cred::username(&user);
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) {
+            let user = self
+                .username(user_from_url)
+                .unwrap_or_else(|| "git".to_string());
+            if let Some(cred) = self.next_ssh_credential(url, &user) {
+                return Ok(cred);
+            }
+        }
+
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(cred) = self.next_plaintext_credential(url, user_from_url) {
+                return Ok(cred);
+            }
+        }
+
+        if allowed.contains(CredentialType::DEFAULT) && !self.tried_default {
+            self.tried_default = true;
+            self.attempted.push("default credentials".to_string());
+            return Cred::default();
+        }
+
+        let attempted = if self.attempted.is_empty() {
+            "none".to_string()
+        } else {
+            self.attempted.join(", ")
+        };
+
+        Err(git2::Error::from_str(&format!(
+            "Authentication failed for {url}, attempted: {attempted}"
+        )))
+    }
+
+    fn username(&self, user_from_url: Option<&str>) -> Option<String> {
+        user_from_url
+            .map(ToString::to_string)
+            .or_else(|| self.settings.username.clone())
+    }
+
+    fn next_ssh_credential(&mut self, url: &str, user: &str) -> Option<Cred> {
+        if self.ssh_methods.is_none() {
+            let mut methods = vec![SshMethod::Agent];
+            if self.settings.ssh_config {
+                if let Some(host) = url_host(url) {
+                    methods.extend(ssh_config_identities(host).into_iter().map(SshMethod::Key));
+                }
+            }
+
+            if let Some(ssh_dir) = dirs::home_dir().map(|home| home.join(".ssh")) {
+                for key in DEFAULT_SSH_KEYS {
+                    let key = SshMethod::Key(ssh_dir.join(key));
+                    if !methods.contains(&key) {
+                        methods.push(key);
+                    }
+                }
+            }
+
+            // Methods are popped from the end
+            methods.reverse();
+            self.ssh_methods = Some(methods);
+        }
+
+        let methods = self.ssh_methods.as_mut()?;
+        while let Some(method) = methods.pop() {
+            let cred = match &method {
+                SshMethod::Agent => {
+                    self.attempted.push("ssh-agent".to_string());
+                    Cred::ssh_key_from_agent(user)
+                }
+                SshMethod::Key(key) if key.exists() => {
+                    self.attempted.push(key.display().to_string());
+                    Cred::ssh_key(user, None, key, None)
+                }
+                // Skip default keys that do not exist
+                SshMethod::Key(_) => continue,
+            };
+
+            if let Ok(cred) = cred {
+                return Some(cred);
+            }
+        }
+
+        None
+    }
+
+    fn next_plaintext_credential(
+        &mut self,
+        url: &str,
+        user_from_url: Option<&str>,
+    ) -> Option<Cred> {
+        if !self.tried_helper {
+            self.tried_helper = true;
+            if let Some(config) = &self.config {
+                self.attempted.push("git credential helper".to_string());
+                let username = self.username(user_from_url);
+                if let Ok(cred) = Cred::credential_helper(config, url, username.as_deref()) {
+                    return Some(cred);
+                }
+            }
+        }
+
+        if !self.tried_env {
+            self.tried_env = true;
+            let username = env::var(USERNAME_ENV)
+                .ok()
+                .or_else(|| self.username(user_from_url));
+            if let (Some(username), Ok(password)) = (username, env::var(PASSWORD_ENV)) {
+                self.attempted
+                    .push(format!("{USERNAME_ENV}/{PASSWORD_ENV}"));
+                return Cred::userpass_plaintext(&username, &password).ok();
+            }
+        }
+
+        None
+    }
+}
+
+// Extract the host from `scheme://[user@]host[:port]/path` or scp-like `[user@]host:path` urls
+fn url_host(url: &str) -> Option<&str> {
+    let authority = match url.split_once("://") {
+        Some((_, rest)) => rest.split('/').next()?,
+        None => url.split(':').next()?,
+    };
+
+    let host = authority.rsplit('@').next()?;
+    let host = host.split(':').next()?;
+    Some(host).filter(|host| !host.is_empty())
+}
+
+// `IdentityFile` entries of the `~/.ssh/config` blocks matching `host`
+fn ssh_config_identities(host: &str) -> Vec<PathBuf> {
+    let Some(home) = dirs::home_dir() else {
+        return vec![];
+    };
+
+    fs::read_to_string(home.join(".ssh").join("config"))
+        .map(|config| parse_ssh_config(&config, host, &home))
+        .unwrap_or_default()
+}
+
+fn parse_ssh_config(config: &str, host: &str, home: &Path) -> Vec<PathBuf> {
+    let mut identities = vec![];
+    // Options before the first `Host` block apply to every host
+    let mut matching = true;
+
+    for line in config.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, value) = line
+            .split_once(|c: char| c.is_whitespace() || c == '=')
+            .map(|(keyword, value)| (keyword, value.trim_start_matches('=').trim()))
+            .unwrap_or((line, ""));
+
+        if keyword.eq_ignore_ascii_case("host") {
+            let patterns: Vec<&str> = value.split_whitespace().collect();
+            let negated = patterns
+                .iter()
+                .filter_map(|pattern| pattern.strip_prefix('!'))
+                .any(|pattern| wildcard_match(pattern, host));
+            matching = !negated
+                && patterns
+                    .iter()
+                    .filter(|pattern| !pattern.starts_with('!'))
+                    .any(|pattern| wildcard_match(pattern, host));
+        } else if keyword.eq_ignore_ascii_case("match") {
+            // `Match` criteria are not supported, ignore the block
+            matching = false;
+        } else if matching && keyword.eq_ignore_ascii_case("identityfile") {
+            let path = value.trim_matches('"');
+            let path = match path.strip_prefix("~/") {
+                Some(path) => home.join(path),
+                None => PathBuf::from(path),
+            };
+            identities.push(path);
+        }
+    }
+
+    identities
+}
+
+// Match ssh config host patterns, supporting `*` and `?`
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::git::credentials::{
+        parse_ssh_config, url_host, wildcard_match, CredentialHandler, PASSWORD_ENV, USERNAME_ENV,
+    };
+    use crate::settings::git::GitSettings;
+    use anyhow::Result;
+    use git2::{Config, CredentialType};
+    use indoc::indoc;
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::path::{Path, PathBuf};
+    use std::{env, fs};
+
+    #[test]
+    fn should_extract_url_host() {
+        assert_that!(url_host("git@github.com:oknozor/dotfiles.git"))
+            .is_equal_to(Some("github.com"));
+        assert_that!(url_host("ssh://git@example.org:2222/dotfiles"))
+            .is_equal_to(Some("example.org"));
+        assert_that!(url_host("https://user@example.org/dotfiles"))
+            .is_equal_to(Some("example.org"));
+    }
+
+    #[test]
+    fn should_match_host_patterns() {
+        assert_that!(wildcard_match("*.example.org", "git.example.org")).is_true();
+        assert_that!(wildcard_match("git?.example.org", "git1.example.org")).is_true();
+        assert_that!(wildcard_match("*", "github.com")).is_true();
+        assert_that!(wildcard_match("*.example.org", "github.com")).is_false();
+    }
+
+    #[test]
+    fn should_parse_ssh_config_identities() {
+        let config = indoc! {r#"
+            IdentityFile ~/.ssh/global
+
+            Host github.com gitlab.com
+                IdentityFile ~/.ssh/forges
+                User git
+
+            Host *.corp !legacy.corp
+                IdentityFile=/keys/corp
+
+            Host *
+                IdentityFile "~/.ssh/fallback"
+        "#};
+        let home = Path::new("/home/bombadil");
+
+        assert_that!(parse_ssh_config(config, "github.com", home)).is_equal_to(vec![
+            home.join(".ssh/global"),
+            home.join(".ssh/forges"),
+            home.join(".ssh/fallback"),
+        ]);
+        assert_that!(parse_ssh_config(config, "git.corp", home)).is_equal_to(vec![
+            home.join(".ssh/global"),
+            PathBuf::from("/keys/corp"),
+            home.join(".ssh/fallback"),
+        ]);
+        assert_that!(parse_ssh_config(config, "legacy.corp", home))
+            .is_equal_to(vec![home.join(".ssh/global"), home.join(".ssh/fallback")]);
+    }
+
+    #[sealed_test]
+    fn should_try_ssh_methods_in_order() -> Result<()> {
+        // Arrange
+        env::set_var("HOME", env::current_dir()?);
+        fs::create_dir(".ssh")?;
+        fs::write(".ssh/id_ed25519", "")?;
+        fs::write(".ssh/id_rsa", "")?;
+        fs::write(".ssh/work", "")?;
+        fs::write(
+            ".ssh/config",
+            "Host example.org\n  IdentityFile ~/.ssh/work\n",
+        )?;
+        let settings = GitSettings {
+            ssh_config: true,
+            ..Default::default()
+        };
+        let mut handler = CredentialHandler::new(&settings, None);
+        let url = "git@example.org:dotfiles.git";
+
+        // Act
+        let mut attempts = 0;
+        let error = loop {
+            match handler.credentials(url, Some("git"), CredentialType::SSH_KEY) {
+                Ok(_) => attempts += 1,
+                Err(err) => break err,
+            }
+        };
+
+        // Assert
+        let home = env::current_dir()?;
+        assert_that!(attempts).is_equal_to(4);
+        assert_that!(error.message().to_string()).is_equal_to(format!(
+            "Authentication failed for {url}, attempted: ssh-agent, {}, {}, {}",
+            home.join(".ssh/work").display(),
+            home.join(".ssh/id_ed25519").display(),
+            home.join(".ssh/id_rsa").display(),
+        ));
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_use_credential_helper_then_env() -> Result<()> {
+        // Arrange
+        fs::write(
+            "gitconfig",
+            "[credential]\n\thelper = \"!f() { echo username=helper; echo password=secret; }; f\"\n",
+        )?;
+        let config = Config::open(Path::new("gitconfig"))?;
+        let mut handler = CredentialHandler::new(&GitSettings::default(), Some(config));
+        env::set_var(USERNAME_ENV, "bombadil");
+        env::set_var(PASSWORD_ENV, "hunter2");
+        let url = "https://example.org/dotfiles.git";
+
+        // Act
+        let helper = handler.credentials(url, None, CredentialType::USER_PASS_PLAINTEXT);
+        let from_env = handler.credentials(url, None, CredentialType::USER_PASS_PLAINTEXT);
+        let exhausted = handler.credentials(url, None, CredentialType::USER_PASS_PLAINTEXT);
+
+        // Assert
+        assert_that!(helper.map(|_| ())).is_ok();
+        assert_that!(from_env.map(|_| ())).is_ok();
+        let error = exhausted.map(|_| ()).unwrap_err().message().to_string();
+        assert_that!(error).ends_with(format!(
+            "attempted: git credential helper, {USERNAME_ENV}/{PASSWORD_ENV}"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,743 @@
+use crate::git::credentials::CredentialHandler;
+use crate::settings::git::GitSettings;
+use anyhow::{anyhow, Result};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{
+    AutotagOption, BranchType, Commit, Config, ErrorCode, FetchOptions, Oid, Progress, PushOptions,
+    RemoteCallbacks, Repository, StatusOptions, SubmoduleUpdateOptions,
+};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+mod credentials;
+
+struct State {
+    progress: Option<Progress<'static>>,
+    total: usize,
+    current: usize,
+    path: Option<PathBuf>,
+    newline: bool,
+}
+
+fn print_clone_progress(state: &mut State) {
+    let stats = state.progress.as_ref().unwrap();
+    let network_pct = (100 * stats.received_objects())
+        .checked_div(stats.total_objects())
+        .unwrap_or(0);
+    let index_pct = (100 * stats.indexed_objects())
+        .checked_div(stats.total_objects())
+        .unwrap_or(0);
+    let co_pct = (100 * state.current).checked_div(state.total).unwrap_or(0);
+    let kbytes = stats.received_bytes() / 1024;
+    if stats.received_objects() == stats.total_objects() {
+        if !state.newline {
+            println!();
+            state.newline = true;
+        }
+        print!(
+            "Resolving deltas {}/{}\r",
+            stats.indexed_deltas(),
+            stats.total_deltas()
+        );
+    } else {
+        print!(
+            "net {:3}% ({:4} kb, {:5}/{:5})  /  idx {:3}% ({:5}/{:5})  \
+             /  chk {:3}% ({:4}/{:4}) {}\r",
+            network_pct,
+            kbytes,
+            stats.received_objects(),
+            stats.total_objects(),
+            index_pct,
+            stats.indexed_objects(),
+            stats.total_objects(),
+            co_pct,
+            state.current,
+            state.total,
+            state
+                .path
+                .as_ref()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        )
+    }
+    io::stdout().flush().unwrap();
+}
+
+/// Options for `bombadil clone`
+#[derive(Debug, Default, Clone)]
+pub struct CloneOptions {
+    /// Branch to checkout instead of the remote HEAD
+    pub branch: Option<String>,
+    /// Revision to checkout after cloning, the HEAD is detached
+    pub rev: Option<String>,
+    /// Create a shallow clone with the given number of commits
+    pub depth: Option<i32>,
+}
+
+/// Derive the clone target directory from a remote address, supporting urls,
+/// scp-like addresses (`git@host:org/repo`) and local paths, with or without `.git`
+pub(crate) fn repository_name(remote: &str) -> Option<&str> {
+    let remote = remote.trim_end_matches('/');
+    let remote = remote.strip_suffix(".git").unwrap_or(remote);
+    remote
+        .rsplit(['/', ':'])
+        .next()
+        .filter(|name| !name.is_empty())
+}
+
+impl State {
+    fn new() -> RefCell<Self> {
+        RefCell::new(State {
+            progress: None,
+            total: 0,
+            current: 0,
+            path: None,
+            newline: false,
+        })
+    }
+}
+
+// Fetch options printing transfer progress and using the user credentials
+fn fetch_options(state: &RefCell<State>, mut credentials: CredentialHandler) -> FetchOptions<'_> {
+    let mut cb = RemoteCallbacks::new();
+    cb.credentials(move |url, user, allowed| credentials.credentials(url, user, allowed));
+    cb.transfer_progress(|stats| {
+        let mut state = state.borrow_mut();
+        state.progress = Some(stats.to_owned());
+        print_clone_progress(&mut state);
+        true
+    });
+
+    let mut fo = FetchOptions::new();
+    fo.remote_callbacks(cb);
+    fo
+}
+
+// A safe checkout printing progress
+fn checkout_builder(state: &RefCell<State>) -> CheckoutBuilder<'_> {
+    let mut co = CheckoutBuilder::new();
+    co.safe().progress(|path, cur, total| {
+        let mut state = state.borrow_mut();
+        state.path = path.map(|p| p.to_path_buf());
+        state.current = cur;
+        state.total = total;
+        if state.progress.is_some() {
+            print_clone_progress(&mut state);
+        }
+    });
+    co
+}
+
+pub(crate) fn clone(
+    remote: &str,
+    path: &Path,
+    options: &CloneOptions,
+    settings: &GitSettings,
+) -> Result<Repository, git2::Error> {
+    let state = State::new();
+    let co = checkout_builder(&state);
+    let credentials = CredentialHandler::new(settings, Config::open_default().ok());
+    let mut fo = fetch_options(&state, credentials);
+    if let Some(depth) = options.depth {
+        fo.depth(depth);
+    }
+
+    let mut builder = RepoBuilder::new();
+    builder.fetch_options(fo).with_checkout(co);
+    if let Some(branch) = &options.branch {
+        builder.branch(branch);
+    }
+
+    let repository = builder.clone(remote, path)?;
+    println!();
+
+    if let Some(rev) = &options.rev {
+        checkout_rev(&repository, rev)?;
+    }
+
+    Ok(repository)
+}
+
+/// Checkout the given revision and detach HEAD
+pub(crate) fn checkout_rev(repository: &Repository, rev: &str) -> Result<(), git2::Error> {
+    let object = repository.revparse_single(rev)?;
+    repository.checkout_tree(&object, Some(CheckoutBuilder::new().safe()))?;
+    repository.set_head_detached(object.peel_to_commit()?.id())
+}
+
+/// Initialize and update submodules recursively
+pub(crate) fn update_submodules(
+    repository: &Repository,
+    settings: &GitSettings,
+) -> Result<(), git2::Error> {
+    for mut submodule in repository.submodules()? {
+        println!("Updating submodule {}", submodule.path().display());
+        let state = State::new();
+        let credentials = CredentialHandler::new(settings, repository.config().ok());
+        let mut options = SubmoduleUpdateOptions::new();
+        options.fetch(fetch_options(&state, credentials));
+        submodule.update(true, Some(&mut options))?;
+        update_submodules(&submodule.open()?, settings)?;
+    }
+
+    Ok(())
+}
+
+/// Clone or update the repository of an external dot in `path`, then checkout `rev`
+/// or the remote default branch. An existing clone is only fetched when `update` is set
+/// or when the requested revision is not checked out.
+pub(crate) fn sync_external(
+    url: &str,
+    rev: Option<&str>,
+    path: &Path,
+    update: bool,
+    settings: &GitSettings,
+) -> Result<()> {
+    let existing = Repository::open(path).ok().filter(|repository| {
+        repository
+            .find_remote("origin")
+            .is_ok_and(|remote| remote.url() == Some(url))
+    });
+
+    let Some(repository) = existing else {
+        // Not cloned yet, or the dot now points to another repository
+        if path.exists() {
+            fs::remove_dir_all(path)?;
+        }
+
+        println!("Cloning external dot {url}");
+        let repository = clone(url, path, &CloneOptions::default(), settings)?;
+        return checkout_external(&repository, rev);
+    };
+
+    let head = repository.head().ok().and_then(|head| head.target());
+    let checked_out = resolve_external_rev(&repository, rev).is_ok_and(|oid| Some(oid) == head);
+
+    if update || !checked_out {
+        println!("Fetching external dot {url}");
+        let state = State::new();
+        let credentials = CredentialHandler::new(settings, repository.config().ok());
+        let mut fo = fetch_options(&state, credentials);
+        fo.download_tags(AutotagOption::All);
+        repository
+            .find_remote("origin")?
+            .fetch::<&str>(&[], Some(&mut fo), None)?;
+        println!();
+        checkout_external(&repository, rev)?;
+    }
+
+    Ok(())
+}
+
+// Branches are resolved against the remote so updates move them forward
+fn resolve_external_rev(repository: &Repository, rev: Option<&str>) -> Result<Oid, git2::Error> {
+    let object = match rev {
+        Some(rev) => repository
+            .revparse_single(&format!("origin/{rev}"))
+            .or_else(|_| repository.revparse_single(rev))?,
+        None => repository.revparse_single("refs/remotes/origin/HEAD")?,
+    };
+
+    Ok(object.peel_to_commit()?.id())
+}
+
+fn checkout_external(repository: &Repository, rev: Option<&str>) -> Result<()> {
+    let oid = resolve_external_rev(repository, rev)
+        .map_err(|err| anyhow!("Unable to resolve {:?} : {}", rev.unwrap_or("HEAD"), err))?;
+    let commit = repository.find_commit(oid)?;
+    repository.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().force()))?;
+    repository.set_head_detached(oid)?;
+    Ok(())
+}
+
+/// Commits and files brought by `bombadil pull`
+#[derive(Debug, Default)]
+pub(crate) struct PullSummary {
+    /// Short id and summary of incoming commits, newest first
+    pub commits: Vec<String>,
+    /// Changed files, absolute paths in the repository working directory
+    pub changed_files: Vec<PathBuf>,
+}
+
+/// Fetch the upstream of the current branch and fast-forward to it.
+/// Uncommitted changes to tracked files abort the pull unless `force` is set.
+pub(crate) fn pull(path: &Path, force: bool, settings: &GitSettings) -> Result<PullSummary> {
+    let repository = Repository::discover(path)?;
+    let workdir = workdir(&repository)?;
+
+    if !force {
+        let mut status_options = StatusOptions::new();
+        status_options.include_untracked(false);
+        let statuses = repository.statuses(Some(&mut status_options))?;
+        if !statuses.is_empty() {
+            return Err(anyhow!(
+                "{:?} has uncommitted changes, commit them or use `--force`",
+                workdir
+            ));
+        }
+    }
+
+    let head = repository.head()?;
+    let branch = head
+        .shorthand()
+        .filter(|_| head.is_branch())
+        .ok_or_else(|| anyhow!("HEAD is detached, checkout a branch to pull"))?
+        .to_string();
+    let head_ref = head.name().unwrap_or_default().to_string();
+    let remote_name = repository.branch_upstream_remote(&head_ref)?;
+    let remote_name = remote_name.as_str().unwrap_or("origin");
+
+    println!("Fetching {remote_name}/{branch}");
+    let state = State::new();
+    let mut remote = repository.find_remote(remote_name)?;
+    let credentials = CredentialHandler::new(settings, repository.config().ok());
+    remote.fetch::<&str>(&[], Some(&mut fetch_options(&state, credentials)), None)?;
+    println!();
+
+    let upstream = repository
+        .find_branch(&branch, BranchType::Local)?
+        .upstream()?
+        .into_reference();
+    let upstream_oid = upstream
+        .target()
+        .ok_or_else(|| anyhow!("Upstream of {branch} has no target"))?;
+    let head_oid = head.target().ok_or_else(|| anyhow!("HEAD has no target"))?;
+
+    let upstream_commit = repository.reference_to_annotated_commit(&upstream)?;
+    let (analysis, _) = repository.merge_analysis(&[&upstream_commit])?;
+
+    if analysis.is_up_to_date() {
+        return Ok(PullSummary::default());
+    }
+
+    if !analysis.is_fast_forward() {
+        return Err(anyhow!(
+            "{branch} has diverged from its upstream, cannot fast-forward"
+        ));
+    }
+
+    let mut revwalk = repository.revwalk()?;
+    revwalk.push(upstream_oid)?;
+    revwalk.hide(head_oid)?;
+    let commits = revwalk
+        .map(|oid| {
+            let commit = repository.find_commit(oid?)?;
+            let id = commit.as_object().short_id()?;
+            Ok(format!(
+                "{} {}",
+                id.as_str().unwrap_or_default(),
+                commit.summary().unwrap_or_default()
+            ))
+        })
+        .collect::<Result<Vec<String>, git2::Error>>()?;
+
+    let old_tree = repository.find_commit(head_oid)?.tree()?;
+    let new_tree = repository.find_commit(upstream_oid)?.tree()?;
+    let diff = repository.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+    let changed_files = diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .map(|path| workdir.join(path))
+        .collect::<BTreeSet<PathBuf>>()
+        .into_iter()
+        .collect();
+
+    // Fast-forward: checkout the upstream tree then move the branch
+    repository.checkout_tree(new_tree.as_object(), Some(&mut checkout_builder(&state)))?;
+    repository
+        .find_reference(&head_ref)?
+        .set_target(upstream_oid, "bombadil pull: fast-forward")?;
+    update_submodules(&repository, settings)?;
+
+    Ok(PullSummary {
+        commits,
+        changed_files,
+    })
+}
+
+/// Files with uncommitted changes, untracked files included, as absolute paths
+pub(crate) fn changed_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let repository = Repository::discover(path)?;
+    let workdir = workdir(&repository)?;
+    let mut status_options = StatusOptions::new();
+    status_options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true);
+
+    let statuses = repository.statuses(Some(&mut status_options))?;
+    let mut changed: Vec<PathBuf> = statuses
+        .iter()
+        .filter(|entry| !entry.status().is_ignored())
+        .filter_map(|entry| entry.path().map(|path| workdir.join(path)))
+        .collect();
+    changed.sort();
+
+    Ok(changed)
+}
+
+/// Stage the given files, either added, modified or removed, and commit them on the current branch
+pub(crate) fn commit(path: &Path, files: &[PathBuf], message: &str) -> Result<Oid> {
+    let repository = Repository::discover(path)?;
+    let workdir = workdir(&repository)?;
+    let mut index = repository.index()?;
+
+    for file in files {
+        let relative = file.strip_prefix(&workdir)?;
+        if file.exists() {
+            index.add_path(relative)?;
+        } else {
+            index.remove_path(relative)?;
+        }
+    }
+
+    index.write()?;
+    let tree = repository.find_tree(index.write_tree()?)?;
+    let signature = repository.signature()?;
+    let parent = match repository.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(err) if err.code() == ErrorCode::UnbornBranch => None,
+        Err(err) => return Err(err.into()),
+    };
+    let parents: Vec<&Commit> = parent.iter().collect();
+
+    Ok(repository.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )?)
+}
+
+/// Push the current branch to its upstream remote, `origin` if none is configured
+pub(crate) fn push(path: &Path, settings: &GitSettings) -> Result<()> {
+    let repository = Repository::discover(path)?;
+    let head = repository.head()?;
+    let branch_ref = head
+        .name()
+        .filter(|_| head.is_branch())
+        .ok_or_else(|| anyhow!("HEAD is detached, checkout a branch to push"))?;
+
+    let remote_name = repository
+        .branch_upstream_remote(branch_ref)
+        .ok()
+        .and_then(|remote| remote.as_str().map(ToString::to_string))
+        .unwrap_or_else(|| "origin".to_string());
+    let mut remote = repository.find_remote(&remote_name)?;
+
+    let rejection = RefCell::new(None);
+    let mut credentials = CredentialHandler::new(settings, repository.config().ok());
+    let mut cb = RemoteCallbacks::new();
+    cb.credentials(|url, user, allowed| credentials.credentials(url, user, allowed));
+    cb.push_update_reference(|reference, status| {
+        if let Some(status) = status {
+            *rejection.borrow_mut() = Some(format!("{reference} rejected: {status}"));
+        }
+        Ok(())
+    });
+
+    let mut push_options = PushOptions::new();
+    push_options.remote_callbacks(cb);
+
+    println!(
+        "Pushing {} to {}",
+        head.shorthand().unwrap_or_default(),
+        remote_name
+    );
+    remote.push(
+        &[format!("{branch_ref}:{branch_ref}")],
+        Some(&mut push_options),
+    )?;
+    drop(push_options);
+
+    match rejection.into_inner() {
+        Some(rejection) => Err(anyhow!(rejection)),
+        None => Ok(()),
+    }
+}
+
+fn workdir(repository: &Repository) -> Result<PathBuf> {
+    Ok(repository
+        .workdir()
+        .ok_or_else(|| anyhow!("{:?} is a bare repository", repository.path()))?
+        .canonicalize()?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::git::{
+        changed_files, clone, commit, pull, push, repository_name, sync_external,
+        update_submodules, CloneOptions,
+    };
+    use crate::settings::git::GitSettings;
+    use crate::test_helpers::commit_file;
+    use git2::build::RepoBuilder;
+    use git2::{Oid, Repository, Signature};
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[sealed_test]
+    fn should_clone_repository() {
+        let path = PathBuf::from("colo-rs");
+        // We are cloning a small repo, unrelated to toml-bombadil on purpose here
+        let clone_result = clone(
+            "https://github.com/oknozor/colo-rs.git",
+            path.as_path(),
+            &CloneOptions::default(),
+            &GitSettings::default(),
+        );
+        assert_that!(clone_result.map(|_| ())).is_ok();
+        assert_that!(PathBuf::from("colo-rs")).exists();
+    }
+
+    #[test]
+    fn should_derive_repository_name() {
+        assert_that!(repository_name("https://github.com/oknozor/dotfiles.git"))
+            .is_equal_to(Some("dotfiles"));
+        assert_that!(repository_name("https://github.com/oknozor/dotfiles"))
+            .is_equal_to(Some("dotfiles"));
+        assert_that!(repository_name("https://github.com/oknozor/dotfiles/"))
+            .is_equal_to(Some("dotfiles"));
+        assert_that!(repository_name("git@github.com:oknozor/dotfiles.git"))
+            .is_equal_to(Some("dotfiles"));
+        assert_that!(repository_name("git@host:dotfiles")).is_equal_to(Some("dotfiles"));
+        assert_that!(repository_name("/srv/git/dotfiles.git")).is_equal_to(Some("dotfiles"));
+        assert_that!(repository_name("https://")).is_none();
+    }
+
+    // Create a local repository with a commit on the default branch and one on a `laptop` branch
+    fn local_remote(path: &Path) -> Result<(Oid, Oid), git2::Error> {
+        let repository = Repository::init(path)?;
+        let signature = Signature::now("bombadil", "bombadil@example.org")?;
+
+        let commit = |file: &str, parent: Option<Oid>, reference: &str| {
+            fs::write(path.join(file), file).expect("write test file");
+            let mut index = repository.index()?;
+            index.add_path(Path::new(file))?;
+            let tree = repository.find_tree(index.write_tree()?)?;
+            let parents = parent
+                .map(|parent| repository.find_commit(parent))
+                .transpose()?;
+            let parents: Vec<_> = parents.iter().collect();
+            repository.commit(
+                Some(reference),
+                &signature,
+                &signature,
+                file,
+                &tree,
+                &parents,
+            )
+        };
+
+        let first = commit("bombadil.toml", None, "HEAD")?;
+        let second = commit("zshrc", Some(first), "refs/heads/laptop")?;
+        Ok((first, second))
+    }
+
+    #[sealed_test]
+    fn should_clone_branch() -> Result<(), git2::Error> {
+        // Arrange
+        local_remote(Path::new("remote"))?;
+        let options = CloneOptions {
+            branch: Some("laptop".to_string()),
+            ..Default::default()
+        };
+
+        // Act
+        let repository = clone(
+            "remote",
+            Path::new("dotfiles"),
+            &options,
+            &GitSettings::default(),
+        )?;
+
+        // Assert
+        assert_that!(repository.head()?.shorthand()).is_equal_to(Some("laptop"));
+        assert_that!(PathBuf::from("dotfiles/zshrc")).exists();
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_checkout_rev() -> Result<(), git2::Error> {
+        // Arrange
+        let (first, _) = local_remote(Path::new("remote"))?;
+        let options = CloneOptions {
+            branch: Some("laptop".to_string()),
+            rev: Some(first.to_string()),
+            ..Default::default()
+        };
+
+        // Act
+        let repository = clone(
+            "remote",
+            Path::new("dotfiles"),
+            &options,
+            &GitSettings::default(),
+        )?;
+
+        // Assert
+        assert_that!(repository.head_detached()?).is_true();
+        assert_that!(repository.head()?.target()).is_equal_to(Some(first));
+        assert_that!(PathBuf::from("dotfiles/zshrc")).does_not_exist();
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_fast_forward_on_pull() -> anyhow::Result<()> {
+        // Arrange
+        local_remote(Path::new("remote"))?;
+        clone(
+            "remote",
+            Path::new("dotfiles"),
+            &CloneOptions::default(),
+            &GitSettings::default(),
+        )?;
+        let incoming = commit_file(Path::new("remote"), "vimrc", "set number")?;
+
+        // Act
+        let summary = pull(Path::new("dotfiles"), false, &GitSettings::default())?;
+
+        // Assert
+        let repository = Repository::open("dotfiles")?;
+        assert_that!(repository.head()?.target()).is_equal_to(Some(incoming));
+        assert_that!(summary.commits).has_length(1);
+        assert_that!(summary.commits[0]).ends_with(" vimrc");
+        assert_that!(summary.changed_files).is_equal_to(vec![PathBuf::from("dotfiles")
+            .canonicalize()?
+            .join("vimrc")]);
+        assert_that!(fs::read_to_string("dotfiles/vimrc")?).is_equal_to("set number".to_string());
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_refuse_to_pull_with_uncommitted_changes() -> anyhow::Result<()> {
+        // Arrange
+        local_remote(Path::new("remote"))?;
+        clone(
+            "remote",
+            Path::new("dotfiles"),
+            &CloneOptions::default(),
+            &GitSettings::default(),
+        )?;
+        commit_file(Path::new("remote"), "vimrc", "set number")?;
+        fs::write("dotfiles/bombadil.toml", "local change")?;
+
+        // Act
+        let refused = pull(Path::new("dotfiles"), false, &GitSettings::default());
+        let forced = pull(Path::new("dotfiles"), true, &GitSettings::default());
+
+        // Assert
+        assert_that!(refused).is_err();
+        assert_that!(forced).is_ok();
+        assert_that!(fs::read_to_string("dotfiles/bombadil.toml")?)
+            .is_equal_to("local change".to_string());
+        assert_that!(PathBuf::from("dotfiles/vimrc")).exists();
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_commit_and_push() -> anyhow::Result<()> {
+        // Arrange
+        local_remote(Path::new("remote"))?;
+        RepoBuilder::new()
+            .bare(true)
+            .clone("remote", Path::new("bare.git"))?;
+        let repository = clone(
+            "bare.git",
+            Path::new("dotfiles"),
+            &CloneOptions::default(),
+            &GitSettings::default(),
+        )?;
+        repository.config()?.set_str("user.name", "bombadil")?;
+        repository
+            .config()?
+            .set_str("user.email", "bombadil@example.org")?;
+        fs::write("dotfiles/vimrc", "set number")?;
+        fs::remove_file("dotfiles/bombadil.toml")?;
+
+        // Act
+        let changed = changed_files(Path::new("dotfiles"))?;
+        let oid = commit(Path::new("dotfiles"), &changed, "Update vim")?;
+        push(Path::new("dotfiles"), &GitSettings::default())?;
+
+        // Assert
+        let workdir = PathBuf::from("dotfiles").canonicalize()?;
+        assert_that!(changed)
+            .is_equal_to(vec![workdir.join("bombadil.toml"), workdir.join("vimrc")]);
+        assert_that!(changed_files(Path::new("dotfiles"))?).is_empty();
+        let bare = Repository::open_bare("bare.git")?;
+        let branch = repository.head()?.name().unwrap_or_default().to_string();
+        assert_that!(bare.find_reference(&branch)?.target()).is_equal_to(Some(oid));
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_sync_external_repository() -> anyhow::Result<()> {
+        // Arrange
+        let (first, _) = local_remote(Path::new("remote"))?;
+        let path = Path::new("external/remote");
+        sync_external(
+            "remote",
+            Some(&first.to_string()),
+            path,
+            false,
+            &GitSettings::default(),
+        )?;
+        let pinned = Repository::open(path)?.head()?.target();
+        let latest = commit_file(Path::new("remote"), "vimrc", "set number")?;
+
+        // Act
+        sync_external("remote", None, path, true, &GitSettings::default())?;
+
+        // Assert
+        assert_that!(pinned).is_equal_to(Some(first));
+        assert_that!(Repository::open(path)?.head()?.target()).is_equal_to(Some(latest));
+        assert_that!(path.join("vimrc")).exists();
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_update_submodules() -> anyhow::Result<()> {
+        // Arrange
+        local_remote(Path::new("theme"))?;
+        local_remote(Path::new("remote"))?;
+        let theme_url = std::env::current_dir()?.join("theme");
+        let remote = Repository::open("remote")?;
+        let mut submodule =
+            remote.submodule(&theme_url.to_string_lossy(), Path::new("theme"), true)?;
+        submodule.clone(None)?;
+        submodule.add_finalize()?;
+        let signature = Signature::now("bombadil", "bombadil@example.org")?;
+        let tree = remote.find_tree(remote.index()?.write_tree()?)?;
+        let parent = remote.head()?.peel_to_commit()?;
+        remote.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Add theme",
+            &tree,
+            &[&parent],
+        )?;
+        let repository = clone(
+            "remote",
+            Path::new("dotfiles"),
+            &CloneOptions::default(),
+            &GitSettings::default(),
+        )?;
+
+        // Act
+        update_submodules(&repository, &GitSettings::default())?;
+
+        // Assert
+        assert_that!(PathBuf::from("dotfiles/theme/bombadil.toml")).exists();
+        Ok(())
+    }
+}
@@ -3,7 +3,8 @@ use std::io::Write;
 use std::path::Path;
 use std::process::{Command, Stdio};
 
-use crate::templating::Variables;
+use crate::secrets;
+use crate::secrets::SecretBackend;
 
 const PGP_HEADER: &str = "-----BEGIN PGP MESSAGE-----\n\n";
 const PGP_FOOTER: &str = "\n-----END PGP MESSAGE-----";
@@ -11,31 +12,88 @@ const PGP_FOOTER: &str = "\n-----END PGP MESSAGE-----";
 #[derive(Clone, Debug)]
 pub struct Gpg {
     pub user_id: String,
+    /// Other users able to decrypt the secrets
+    pub recipients: Vec<String>,
 }
 
 impl Gpg {
     pub(crate) fn new(user_id: &str) -> Self {
         Gpg {
             user_id: user_id.to_string(),
+            recipients: vec![],
         }
     }
 
+    /// Also encrypt to the given recipients
+    pub(crate) fn with_recipients(mut self, recipients: &[String]) -> Self {
+        for recipient in recipients {
+            if *recipient != self.user_id && !self.recipients.contains(recipient) {
+                self.recipients.push(recipient.clone());
+            }
+        }
+
+        self
+    }
+
+    /// Long key ids a secret is encrypted to
+    pub(crate) fn key_ids(stored: &str) -> Result<Vec<String>> {
+        let pgp_message = format!("{}{}{}", PGP_HEADER, stored, PGP_FOOTER);
+        let mut command = Command::new("gpg");
+        command
+            .args(["--batch", "--list-only", "--list-packets"])
+            .stderr(Stdio::null());
+        let packets = secrets::pipe(&mut command, &pgp_message)?;
+
+        Ok(packets
+            .lines()
+            .filter(|line| line.starts_with(":pubkey enc packet:"))
+            .filter_map(|line| line.split("keyid ").nth(1))
+            .map(|key_id| key_id.trim().to_string())
+            .collect())
+    }
+
+    /// The primary user id of a key in the local keyring
+    pub(crate) fn user_id_of(key_id: &str) -> Option<String> {
+        let output = Command::new("gpg")
+            .args(["--batch", "--with-colons", "--list-keys", key_id])
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find(|line| line.starts_with("uid:"))
+            .and_then(|line| line.split(':').nth(9))
+            .map(ToString::to_string)
+    }
+
     pub(crate) fn push_secret<S: AsRef<Path> + ?Sized>(
         &self,
         key: &str,
         value: &str,
         var_file: &S,
+        blob: bool,
     ) -> Result<()> {
-        let mut vars = Variables::from_path(var_file.as_ref())?;
-        let encrypted = self.encrypt(value)?;
-        let encrypted = encrypted.replace(PGP_HEADER, "");
-        let encrypted = encrypted.replace(PGP_FOOTER, "");
-        vars.push_secret(key, &encrypted);
-        let toml = toml::to_string(&vars)?;
-        std::fs::write(var_file, toml)?;
-        println!("Added {} : {}", key, value);
+        secrets::push_secret(self, key, value, var_file.as_ref(), blob)
+    }
 
-        Ok(())
+    /// Encrypt a whole file to an armored message, for every recipient
+    pub(crate) fn encrypt_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut command = Command::new("gpg");
+        command
+            .args(["--encrypt", "--armor", "-r", &self.user_id])
+            .args(
+                self.recipients
+                    .iter()
+                    .flat_map(|recipient| ["-r", recipient]),
+            );
+        secrets::pipe_file(&mut command, path)
+    }
+
+    /// Decrypt a whole file, either armored or binary
+    pub(crate) fn decrypt_file(path: &Path) -> Result<Vec<u8>> {
+        secrets::pipe_file(Command::new("gpg").args(["--decrypt", "-q"]), path)
     }
 
     pub(crate) fn decrypt_secret(&self, content: &str) -> Result<String> {
@@ -49,6 +107,11 @@ impl Gpg {
             .arg("--armor")
             .arg("-r")
             .arg(&self.user_id)
+            .args(
+                self.recipients
+                    .iter()
+                    .flat_map(|recipient| ["-r", recipient]),
+            )
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn()
@@ -111,14 +174,29 @@ impl Gpg {
     }
 }
 
+impl SecretBackend for Gpg {
+    fn encrypt(&self, _key: &str, value: &str) -> Result<String> {
+        let encrypted = Gpg::encrypt(self, value)?;
+        let encrypted = encrypted.replace(PGP_HEADER, "");
+        Ok(encrypted.replace(PGP_FOOTER, ""))
+    }
+
+    fn decrypt(&self, _key: &str, stored: &str) -> Result<String> {
+        self.decrypt_secret(stored)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::gpg::Gpg;
+    use crate::secrets::{SecretBackend, VarFile};
     use anyhow::Result;
     use cmd_lib::run_cmd;
     use sealed_test::prelude::*;
     use speculoos::prelude::*;
     use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
     use toml::Value;
 
     const GPG_ID: &str = "test@toml.bombadil.org";
@@ -170,7 +248,7 @@ mod test {
     fn should_push_to_var() -> Result<()> {
         let gpg = Gpg::new(GPG_ID);
         std::fs::write("vars.toml", "")?;
-        gpg.push_secret("key", "value", "vars.toml")?;
+        gpg.push_secret("key", "value", "vars.toml", false)?;
 
         let result = std::fs::read_to_string("vars.toml")?;
         let toml: Value = toml::from_str(&result)?;
@@ -184,7 +262,7 @@ mod test {
     fn should_decrypt_from_file() -> Result<()> {
         let gpg = Gpg::new(GPG_ID);
         std::fs::write("vars.toml", "")?;
-        gpg.push_secret("key", "value", "vars.toml")?;
+        gpg.push_secret("key", "value", "vars.toml", false)?;
 
         let result = std::fs::read_to_string("vars.toml")?;
         let toml: Value = toml::from_str(&result)?;
@@ -200,4 +278,56 @@ mod test {
         assert_eq!(decrypted, "value");
         Ok(())
     }
+
+    #[sealed_test(before = gpg_setup())]
+    fn should_decrypt_armored_and_binary_files() -> Result<()> {
+        // Arrange
+        let gpg = Gpg::new(GPG_ID);
+        std::fs::write("netrc", "machine example.org password hunter2\n")?;
+        run_cmd!(gpg --encrypt -r $GPG_ID -o binary.gpg netrc)?;
+
+        // Act
+        let armored = gpg.encrypt_file(Path::new("netrc"))?;
+        std::fs::write("armored.gpg", &armored)?;
+        let from_armored = Gpg::decrypt_file(Path::new("armored.gpg"))?;
+        let from_binary = Gpg::decrypt_file(Path::new("binary.gpg"))?;
+
+        // Assert
+        assert_that!(String::from_utf8(armored)?).starts_with("-----BEGIN PGP MESSAGE-----");
+        assert_that!(from_armored).is_equal_to(b"machine example.org password hunter2\n".to_vec());
+        assert_that!(from_binary).is_equal_to(from_armored);
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_encrypt_to_every_recipient() -> Result<()> {
+        // Arrange
+        let gnupg_home = env::current_dir()?.join("gnupg");
+        std::fs::create_dir(&gnupg_home)?;
+        std::fs::set_permissions(&gnupg_home, std::fs::Permissions::from_mode(0o700))?;
+        env::set_var("GNUPGHOME", &gnupg_home);
+        gpg_setup();
+        run_cmd!(gpg --batch --passphrase "" --quick-gen-key "Second <second@toml.bombadil.org>" future-default default never)?;
+        let gpg = Gpg::new(GPG_ID).with_recipients(&["second@toml.bombadil.org".to_string()]);
+
+        // Act
+        let encrypted = SecretBackend::encrypt(&gpg, "key", "value")?;
+        std::fs::write("vars.toml", "")?;
+        gpg.push_secret("key", "value", "vars.toml", false)?;
+
+        // Assert
+        let key_ids = Gpg::key_ids(&encrypted)?;
+        let user_ids: Vec<String> = key_ids
+            .iter()
+            .filter_map(|key_id| Gpg::user_id_of(key_id))
+            .collect();
+        assert_that!(user_ids).contains_all_of(&[
+            &"Toml Bombadil <test@toml.bombadil.org>".to_string(),
+            &"Second <second@toml.bombadil.org>".to_string(),
+        ]);
+        let recipients = VarFile::open(Path::new("vars.toml"))?.recipients()?;
+        assert_that!(recipients.gpg_secrets).is_equal_to(1);
+        assert_that!(recipients.gpg.values().collect::<Vec<_>>()).is_equal_to(vec![&1, &1]);
+        Ok(())
+    }
 }
@@ -1,68 +1,416 @@
+use crate::templating::Variables;
 use anyhow::{anyhow, Result};
 use colored::*;
 use serde::{Deserialize, Serialize};
-use std::io::{BufRead, BufReader};
-use std::process::{Command, Stdio};
+use std::collections::HashMap;
+use std::fmt::{self, Formatter};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+const DEFAULT_SHELL: &str = "sh";
+
+/// A shell command run before or after linking dots, either declared
+/// as a plain command string or as a table. Commands are tera templates rendered
+/// with the same variables as dots:
+/// ```toml
+/// posthooks = [
+///     "swaymsg reload",
+///     { name = "nvim", command = "nvim --headless +PackerSync +qa", timeout = 60, on_failure = "abort" },
+/// ]
+/// ```
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(from = "HookDefinition")]
 pub struct Hook {
+    /// The command to run
     pub command: String,
+    /// A name displayed instead of the command
+    pub name: Option<String>,
+    /// Working directory, relative to the dotfiles directory if not absolute
+    pub cwd: Option<PathBuf>,
+    /// Additional environment variables
+    pub env: HashMap<String, String>,
+    /// Shell used to run the command, `sh` by default
+    pub shell: Option<String>,
+    /// Kill the hook after this many seconds
+    pub timeout: Option<u64>,
+    /// What to do when the hook fails
+    pub on_failure: FailurePolicy,
+    /// Expose decrypted secrets to the command template
+    pub secrets: bool,
+    /// Capture the command output in `.dots/logs/`
+    pub log: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Stop the installation
+    Abort,
+    /// Print the error and continue
+    #[default]
+    Warn,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HookDefinition {
+    Command(String),
+    Table {
+        command: String,
+        name: Option<String>,
+        cwd: Option<PathBuf>,
+        #[serde(default)]
+        env: HashMap<String, String>,
+        shell: Option<String>,
+        timeout: Option<u64>,
+        #[serde(default)]
+        on_failure: FailurePolicy,
+        #[serde(default)]
+        secrets: bool,
+        #[serde(default)]
+        log: bool,
+    },
+}
+
+impl From<HookDefinition> for Hook {
+    fn from(definition: HookDefinition) -> Self {
+        match definition {
+            HookDefinition::Command(command) => Hook::new(&command),
+            HookDefinition::Table {
+                command,
+                name,
+                cwd,
+                env,
+                shell,
+                timeout,
+                on_failure,
+                secrets,
+                log,
+            } => Hook {
+                command,
+                name,
+                cwd,
+                env,
+                shell,
+                timeout,
+                on_failure,
+                secrets,
+                log,
+            },
+        }
+    }
 }
 
 impl Hook {
-    pub(crate) fn run(&self) -> Result<()> {
-        let command_display = format!("`{}`", &self.command.green());
+    /// Run the hook, stdout and stderr are streamed concurrently, each line prefixed
+    /// with the hook name. When a log directory is provided, output is also captured there.
+    pub(crate) fn run(&self, log_dir: Option<&Path>) -> HookResult {
+        let start = Instant::now();
+        let status = self
+            .execute(log_dir)
+            .unwrap_or_else(|err| HookStatus::Error(err.to_string()));
+
+        HookResult {
+            name: self.display_name().to_string(),
+            status,
+            duration: start.elapsed(),
+        }
+    }
+
+    fn execute(&self, log_dir: Option<&Path>) -> Result<HookStatus> {
+        let command_display = match &self.name {
+            Some(name) => format!("{} (`{}`)", name.green(), &self.command),
+            None => format!("`{}`", &self.command.green()),
+        };
         println!("Running install hook : {}", command_display);
 
-        let mut child = Command::new("sh")
-            .args(["-c", &self.command])
-            .stderr(Stdio::piped())
+        let shell = self.shell.as_deref().unwrap_or(DEFAULT_SHELL);
+        let mut shell = shell.split_whitespace();
+        let program = shell.next().unwrap_or(DEFAULT_SHELL);
+
+        let mut command = Command::new(program);
+        command
+            .args(shell)
+            .arg("-c")
+            .arg(&self.command)
+            .envs(&self.env)
             .stdout(Stdio::piped())
-            .spawn()?;
-
-        BufReader::new(child.stdout.take().unwrap())
-            .lines()
-            .for_each(|line| println!("{}", line.unwrap_or_else(|_| "".into())));
-
-        BufReader::new(child.stderr.take().unwrap())
-            .lines()
-            .for_each(|line| eprintln!("{}", line.unwrap_or_else(|_| "".into())));
-
-        child
-            .wait()
-            .map(|exit_code| {
-                if exit_code.success() {
-                    Ok(())
-                } else {
-                    Err(anyhow!("Hook run failed with status {}", exit_code))
-                }
+            .stderr(Stdio::piped());
+
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+
+        // Run hooks with a timeout in their own process group,
+        // so we can kill the command and all of its children
+        if self.timeout.is_some() {
+            command.process_group(0);
+        }
+
+        let log = match (self.log, log_dir) {
+            (true, Some(log_dir)) => {
+                fs::create_dir_all(log_dir)?;
+                let path = log_dir.join(format!("{}.log", self.log_name()));
+                Some(Mutex::new(File::create(path)?))
+            }
+            _ => None,
+        };
+
+        let mut child = command.spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let prefix = format!("[{}]", self.prefix()).dimmed().to_string();
+
+        // Read both pipes at the same time, a hook filling its stderr pipe
+        // would otherwise block forever while we wait on stdout
+        thread::scope(|scope| {
+            scope.spawn(|| forward_lines(stdout, log.as_ref(), |line| println!("{prefix} {line}")));
+            scope
+                .spawn(|| forward_lines(stderr, log.as_ref(), |line| eprintln!("{prefix} {line}")));
+
+            let exit_status = match self.timeout {
+                None => Some(child.wait()?),
+                Some(timeout) => Hook::wait_timeout(&mut child, Duration::from_secs(timeout))?,
+            };
+
+            Ok(match exit_status {
+                Some(exit_status) if exit_status.success() => HookStatus::Success,
+                Some(exit_status) => match exit_status.code() {
+                    Some(code) => HookStatus::Failed(code),
+                    None => HookStatus::Killed,
+                },
+                None => HookStatus::Error(format!(
+                    "timed out after {}s",
+                    self.timeout.unwrap_or_default()
+                )),
             })
-            .unwrap()
+        })
+    }
+
+    /// Render the command template, secrets are only available
+    /// to hooks explicitly allowing them. Commands without any `{{` or `{%` are run verbatim,
+    /// so shell expansions such as `${#array[@]}` are not mistaken for a tera comment.
+    pub(crate) fn render(&self, vars: &Variables) -> Result<Hook> {
+        if !self.command.contains("{{") && !self.command.contains("{%") {
+            return Ok(self.clone());
+        }
+
+        let command = if self.secrets {
+            vars.render_str(&self.command)
+        } else {
+            Variables {
+                inner: vars.without_secrets(),
+            }
+            .render_str(&self.command)
+        }
+        .map_err(|err| {
+            anyhow!(
+                "Failed to render hook command `{}`: {:?}",
+                self.command,
+                err
+            )
+        })?;
+
+        Ok(Hook {
+            command,
+            ..self.clone()
+        })
+    }
+
+    // Wait for the child to exit, killing its process group on timeout
+    fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>> {
+        let start = Instant::now();
+        loop {
+            if let Some(exit_status) = child.try_wait()? {
+                return Ok(Some(exit_status));
+            }
+
+            if start.elapsed() >= timeout {
+                // The child leads its own process group, its id is the group id
+                let pgid = libc::pid_t::try_from(child.id())?;
+                if unsafe { libc::killpg(pgid, libc::SIGKILL) } != 0 {
+                    let err = io::Error::last_os_error();
+                    // The group may have exited since the last check
+                    if err.raw_os_error() != Some(libc::ESRCH) {
+                        return Err(anyhow!("Failed to kill timed out hook : {}", err));
+                    }
+                }
+
+                child.wait()?;
+                return Ok(None);
+            }
+
+            thread::sleep(Duration::from_millis(20));
+        }
     }
 
     pub fn new(command: &str) -> Self {
         let command = command.to_owned();
-        Hook { command }
+        Hook {
+            command,
+            ..Default::default()
+        }
+    }
+
+    /// Resolve the hook working directory against the dotfiles directory,
+    /// hooks without `cwd` run in the dotfiles directory
+    pub(crate) fn with_default_cwd(mut self, dotfiles_dir: &Path) -> Self {
+        let cwd = match &self.cwd {
+            Some(cwd) => {
+                let cwd = cwd.to_string_lossy();
+                let cwd = shellexpand::tilde(cwd.as_ref());
+                dotfiles_dir.join(cwd.as_ref())
+            }
+            None => dotfiles_dir.to_path_buf(),
+        };
+
+        self.cwd = Some(cwd);
+        self
+    }
+
+    fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.command)
     }
+
+    // Output prefix, either the hook name or the program invoked
+    fn prefix(&self) -> &str {
+        self.name
+            .as_deref()
+            .or_else(|| self.command.split_whitespace().next())
+            .unwrap_or_default()
+    }
+
+    // A file name safe version of the hook display name
+    fn log_name(&self) -> String {
+        self.display_name()
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '-' })
+            .collect()
+    }
+}
+
+/// The outcome of a hook run, displayed in the install summary
+#[derive(Debug)]
+pub(crate) struct HookResult {
+    pub name: String,
+    pub status: HookStatus,
+    pub duration: Duration,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum HookStatus {
+    Success,
+    /// The command exited with a non-zero exit code
+    Failed(i32),
+    /// The command was terminated by a signal
+    Killed,
+    /// The hook could not run to completion
+    Error(String),
+}
+
+impl HookResult {
+    pub(crate) fn success(&self) -> bool {
+        self.status == HookStatus::Success
+    }
+}
+
+impl fmt::Display for HookStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HookStatus::Success => write!(f, "exit code 0"),
+            HookStatus::Failed(code) => write!(f, "exit code {code}"),
+            HookStatus::Killed => write!(f, "killed by signal"),
+            HookStatus::Error(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+// Print each line of a hook output stream and append it to the log file if any
+fn forward_lines(stream: impl Read, log: Option<&Mutex<File>>, print: impl Fn(&str)) {
+    for line in BufReader::new(stream).lines() {
+        let line = line.unwrap_or_default();
+        print(&line);
+        if let Some(log) = log {
+            let mut log = log.lock().expect("Hook log lock poisoned");
+            let _ = writeln!(log, "{line}");
+        }
+    }
+}
+
+/// Render and run hooks in order, failing hooks are reported unless their failure policy is
+/// [`FailurePolicy::Abort`], in which case remaining hooks are skipped and the error returned.
+/// Output of hooks with `log = true` is captured in `log_dir`.
+pub(crate) fn run_all<'a>(
+    hooks: impl IntoIterator<Item = &'a Hook>,
+    vars: &Variables,
+    log_dir: Option<&Path>,
+) -> Result<Vec<HookResult>> {
+    let mut results = vec![];
+    for hook in hooks {
+        let result = match hook.render(vars) {
+            Ok(hook) => hook.run(log_dir),
+            Err(err) => HookResult {
+                name: hook.display_name().to_string(),
+                status: HookStatus::Error(err.to_string()),
+                duration: Duration::ZERO,
+            },
+        };
+
+        if !result.success() {
+            match hook.on_failure {
+                FailurePolicy::Abort => {
+                    return Err(anyhow!(
+                        "Hook '{}' failed, aborting: {}",
+                        result.name,
+                        result.status
+                    ))
+                }
+                FailurePolicy::Warn => {
+                    let message = format!("Hook '{}' failed: {}", result.name, result.status);
+                    eprintln!("{}", message.red());
+                }
+            }
+        }
+
+        results.push(result);
+    }
+
+    Ok(results)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::hook::Hook;
+    use crate::hook::{run_all, FailurePolicy, Hook, HookStatus};
+    use crate::templating::Variables;
+    use anyhow::Result;
+    use indoc::indoc;
+    use sealed_test::prelude::*;
+    use serde::Deserialize;
     use speculoos::prelude::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
 
     #[test]
     fn should_run_command() {
         // Arrange
         let hook = Hook {
             command: "echo hello world".to_string(),
+            ..Default::default()
         };
 
         // Act
-        let result = hook.run();
+        let result = hook.run(None);
 
         // Assert
-        assert_that!(result).is_ok();
+        assert_that!(result.status).is_equal_to(HookStatus::Success);
     }
 
     #[test]
@@ -70,12 +418,206 @@ mod tests {
         // Arrange
         let hook = Hook {
             command: "azmroih".to_string(),
+            ..Default::default()
+        };
+
+        // Act
+        let result = hook.run(None);
+
+        // Assert
+        assert_that!(result.status).is_equal_to(HookStatus::Failed(127));
+    }
+
+    #[test]
+    fn should_kill_hook_on_timeout() {
+        // Arrange
+        let hook = Hook {
+            command: "sleep 5".to_string(),
+            timeout: Some(1),
+            ..Default::default()
         };
 
         // Act
-        let result = hook.run();
+        let start = Instant::now();
+        let result = hook.run(None);
+
+        // Assert
+        assert_that!(result.success()).is_false();
+        assert_that!(start.elapsed()).is_less_than(Duration::from_secs(5));
+    }
+
+    #[sealed_test]
+    fn should_run_with_cwd_env_and_shell() -> Result<()> {
+        // Arrange
+        fs::create_dir("hooks")?;
+        let hook = Hook {
+            command: "echo $GREETING > greeting".to_string(),
+            cwd: Some(PathBuf::from("hooks")),
+            env: HashMap::from([("GREETING".to_string(), "hello".to_string())]),
+            shell: Some("bash -e".to_string()),
+            ..Default::default()
+        }
+        .with_default_cwd(&std::env::current_dir()?);
+
+        // Act
+        let result = hook.run(None);
+
+        // Assert
+        assert_that!(result.success()).is_true();
+        assert_that!(fs::read_to_string("hooks/greeting")?).is_equal_to("hello\n".to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn should_stream_large_output_on_both_pipes() {
+        // Arrange
+        let hook = Hook {
+            command: "seq 1 100000 >&2 && seq 1 100000".to_string(),
+            timeout: Some(30),
+            ..Default::default()
+        };
+
+        // Act
+        let result = hook.run(None);
+
+        // Assert
+        assert_that!(result.status).is_equal_to(HookStatus::Success);
+    }
+
+    #[sealed_test]
+    fn should_capture_output_in_log_file() -> Result<()> {
+        // Arrange
+        let hook = Hook {
+            command: "echo out && echo err >&2".to_string(),
+            name: Some("reload sway".to_string()),
+            log: true,
+            ..Default::default()
+        };
+
+        // Act
+        let result = hook.run(Some(Path::new("logs")));
+
+        // Assert
+        assert_that!(result.success()).is_true();
+        let log = fs::read_to_string("logs/reload-sway.log")?;
+        assert_that!(log).contains("out\n");
+        assert_that!(log).contains("err\n");
+        Ok(())
+    }
+
+    #[test]
+    fn should_abort_on_failure() {
+        // Arrange
+        let hooks = [
+            Hook {
+                command: "false".to_string(),
+                on_failure: FailurePolicy::Abort,
+                ..Default::default()
+            },
+            Hook::new("echo never"),
+        ];
+
+        // Act
+        let result = run_all(&hooks, &Variables::default(), None);
 
         // Assert
         assert_that!(result).is_err();
     }
+
+    #[test]
+    fn should_warn_on_failure() {
+        // Arrange
+        let hooks = [Hook::new("false"), Hook::new("true")];
+
+        // Act
+        let result = run_all(&hooks, &Variables::default(), None);
+
+        // Assert
+        assert_that!(result).is_ok().has_length(2);
+    }
+
+    #[test]
+    fn should_render_command() -> Result<()> {
+        // Arrange
+        let vars: Variables = toml::from_str(indoc! {r#"
+            gtk_theme = "Adwaita-dark"
+            [secrets]
+            token = "hunter2"
+        "#})?;
+        let hook = Hook::new("gsettings set org.gnome.desktop.interface gtk-theme {{ gtk_theme }}");
+
+        // Act
+        let hook = hook.render(&vars)?;
+
+        // Assert
+        assert_that!(hook.command).is_equal_to(
+            "gsettings set org.gnome.desktop.interface gtk-theme Adwaita-dark".to_string(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_shell_expansions_verbatim() -> Result<()> {
+        // Arrange
+        let vars: Variables = toml::from_str(r#"gtk_theme = "Adwaita-dark""#)?;
+        let verbatim = Hook::new(r#"themes=(a b); echo "${#themes[@]}""#);
+        let escaped = Hook::new(r#"echo {{ gtk_theme }} {% raw %}"${#themes[@]}"{% endraw %}"#);
+
+        // Act
+        let verbatim_rendered = verbatim.render(&vars)?;
+        let escaped = escaped.render(&vars)?;
+
+        // Assert
+        assert_that!(verbatim_rendered.command).is_equal_to(verbatim.command);
+        assert_that!(escaped.command)
+            .is_equal_to(r#"echo Adwaita-dark "${#themes[@]}""#.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn should_only_render_secrets_when_allowed() -> Result<()> {
+        // Arrange
+        let vars: Variables = toml::from_str(indoc! {r#"
+            [secrets]
+            token = "hunter2"
+        "#})?;
+        let hook = Hook::new("login {{ secrets.token }}");
+        let allowed = Hook {
+            secrets: true,
+            ..hook.clone()
+        };
+
+        // Act
+        let denied = hook.render(&vars);
+        let allowed = allowed.render(&vars)?;
+
+        // Assert
+        assert_that!(denied).is_err();
+        assert_that!(allowed.command).is_equal_to("login hunter2".to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn should_deserialize_hook_definitions() -> Result<()> {
+        #[derive(Deserialize)]
+        struct Hooks {
+            posthooks: Vec<Hook>,
+        }
+
+        // Act
+        let hooks: Hooks = toml::from_str(
+            r#"posthooks = [
+                "swaymsg reload",
+                { name = "nvim", command = "nvim --headless", timeout = 60, on_failure = "abort" }
+            ]"#,
+        )?;
+
+        // Assert
+        assert_that!(hooks.posthooks[0].command).is_equal_to("swaymsg reload".to_string());
+        assert_that!(hooks.posthooks[0].on_failure).is_equal_to(FailurePolicy::Warn);
+        assert_that!(hooks.posthooks[1].name).is_equal_to(Some("nvim".to_string()));
+        assert_that!(hooks.posthooks[1].timeout).is_equal_to(Some(60));
+        assert_that!(hooks.posthooks[1].on_failure).is_equal_to(FailurePolicy::Abort);
+        Ok(())
+    }
 }
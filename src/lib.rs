@@ -1,39 +1,59 @@
+// Some older tests trip these lints, allow them rather than rewriting the tests.
+#![cfg_attr(
+    test,
+    allow(clippy::needless_borrows_for_generic_args, clippy::redundant_locals)
+)]
+
 use self::settings::profiles::Profile;
+pub use crate::check::Problem;
 use crate::display::links;
-use crate::dots::{DotVar, LinkResult};
+use crate::dots::{DotVar, LinkResult, SecretRenders};
+pub use crate::git::CloneOptions;
 use crate::gpg::Gpg;
 use crate::hook::Hook;
 use crate::paths::{unlink, DotPaths};
+pub use crate::secrets::SecretInput;
+use crate::secrets::VarFile;
 use crate::state::BombadilState;
+use crate::systemd::UnitSnapshot;
 use crate::templating::Variables;
 use anyhow::{anyhow, Result};
 use colored::*;
-use ignore_files::IgnoreFilter;
+use git2::Repository;
+use rayon::prelude::*;
 use serde_json::{json, Value};
-use settings::dots::Dot;
+use settings::dots::{Dot, DotOrigin};
+use settings::git::GitSettings;
+use settings::packages::PackageSettings;
+use settings::secrets::SecretSettings;
+use settings::watch::WatchSettings;
 use settings::Settings;
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::{IsTerminal, Write};
 use std::os::unix;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::process::Command;
+use std::sync::{Arc, OnceLock};
 use std::{fs, io};
-use watchexec::sources::fs::Watcher;
-use watchexec::{error::RuntimeError, Watchexec};
-use watchexec_events::filekind::FileEventKind;
-use watchexec_events::Tag;
-use watchexec_filterer_ignore::IgnoreFilterer;
 
+mod check;
 mod display;
 mod dots;
 mod error;
 mod git;
 mod gpg;
 mod hook;
+mod packages;
 pub mod paths;
+mod secrets;
 pub mod settings;
 mod state;
+mod systemd;
 mod templating;
+#[cfg(test)]
+mod test_helpers;
+mod watch;
 
 pub(crate) const BOMBADIL_CONFIG: &str = "bombadil.toml";
 
@@ -47,18 +67,86 @@ pub struct Bombadil {
     dots: HashMap<String, Dot>,
     // Variables for the tera template context
     vars: Variables,
+    // Var files the template context was loaded from, relative to the dotfiles directory
+    var_paths: Vec<PathBuf>,
+    // Resolved paths of the imported settings
+    imports: Vec<PathBuf>,
+    // Number of threads rendering dots, defaults to the number of CPUs
+    jobs: Option<usize>,
+    // Thread pool rendering dots, built with `jobs` threads on first use
+    render_pool: OnceLock<Arc<rayon::ThreadPool>>,
+    // Settings for `bombadil watch`
+    watch: WatchSettings,
+    // Package dependencies, including the ones added by enabled profiles
+    packages: PackageSettings,
+    // Git authentication settings
+    git: GitSettings,
+    // Secret storage settings
+    secrets: SecretSettings,
     // Pre-hook commands, run before `bombadil-link`
     prehooks: Vec<Hook>,
     // Post-hook commands, run after `bombadil-link`
     posthooks: Vec<Hook>,
+    // Hook commands, run before `bombadil-unlink` removes symlinks
+    pre_unlink: Vec<Hook>,
+    // Hook commands, run after `bombadil-unlink` removed symlinks
+    post_unlink: Vec<Hook>,
+    // Hook commands, run after the first `bombadil-link`
+    on_first_install: Vec<Hook>,
+    // Hook commands, run after a `bombadil-link` with different profiles than the previous one
+    on_profile_change: Vec<Hook>,
     // Available profiles
     profiles: HashMap<String, Profile>,
+    // Profiles to enable per hostname when cloning
+    hosts: HashMap<String, Vec<String>>,
     // Profiles enabled for this instance
     profile_enabled: Vec<String>,
     // A GPG user id, linking to user encryption/decryption key via gnupg
     gpg: Option<Gpg>,
 }
 
+// What `Bombadil::link` did once dots were rendered
+struct Linked {
+    // Rendered copies containing secrets
+    secret_renders: BTreeSet<PathBuf>,
+    // An error raised after rendering, by a symlink, systemd or an aborting posthook,
+    // returned once the rendered secrets and new symlinks are tracked
+    error: Option<anyhow::Error>,
+}
+
+// The machine hostname used to match `[hosts]` rules
+fn hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| {
+            Command::new("hostname")
+                .output()
+                .ok()
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+        })
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+}
+
+// Parse a comma separated list of profile names or 1-based indexes in `available`
+fn parse_profile_selection(input: &str, available: &[String]) -> Result<Vec<String>> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|selection| !selection.is_empty())
+        .map(|selection| {
+            let profile = match selection.parse::<usize>() {
+                Ok(idx) => idx.checked_sub(1).and_then(|idx| available.get(idx)),
+                Err(_) => available.iter().find(|profile| *profile == selection),
+            };
+
+            profile
+                .cloned()
+                .ok_or_else(|| anyhow!("Unknown profile `{selection}`"))
+        })
+        .collect()
+}
+
 /// Enable or disable GPG encryption when linking dotfiles
 pub enum Mode {
     Gpg,
@@ -68,27 +156,104 @@ pub enum Mode {
 impl Bombadil {
     /// Given a git remote address, will clone the repository to the target path
     /// and install the dotfiles according to the "bombadil.toml" configuration inside the
-    /// repo root.
+    /// repo root. The target defaults to the repository name, if it already contains
+    /// a git repository the clone is skipped and installation resumed.
+    ///
+    /// When no profiles are given, the ones matching the current hostname in `[hosts]` are enabled,
+    /// otherwise available profiles are prompted for when running in a terminal.
     pub fn install_from_remote(
         remote: &str,
-        path: PathBuf,
+        target: Option<PathBuf>,
+        options: &CloneOptions,
         profiles: Option<Vec<&str>>,
         force: bool,
     ) -> Result<()> {
-        git::clone(remote, path.as_path())?;
+        let path = match target {
+            Some(path) => path,
+            None => git::repository_name(remote)
+                .map(PathBuf::from)
+                .ok_or_else(|| {
+                    anyhow!("Cannot derive a target directory from {remote}, use `--target`")
+                })?,
+        };
+
+        let is_empty_dir = path
+            .read_dir()
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(false);
+
+        if !path.exists() || is_empty_dir {
+            println!("Cloning {remote} in {path:?}");
+            // Use the git settings of the current configuration, if any
+            let git_settings = Settings::get()
+                .map(|settings| settings.settings.git)
+                .unwrap_or_default();
+            let repository = git::clone(remote, path.as_path(), options, &git_settings)?;
+            git::update_submodules(&repository, &git_settings)?;
+        } else if let Ok(repository) = Repository::open(&path) {
+            println!("{path:?} is already cloned, resuming installation");
+            if let Some(rev) = &options.rev {
+                git::checkout_rev(&repository, rev)?;
+            }
+            let git_settings = Settings::get()
+                .map(|settings| settings.settings.git)
+                .unwrap_or_default();
+            git::update_submodules(&repository, &git_settings)?;
+        } else {
+            return Err(anyhow!(
+                "{:?} already exists and is not a git repository",
+                path
+            ));
+        }
+
         Bombadil::link_self_config(Some(path.join(BOMBADIL_CONFIG)))?;
 
         let mut bombadil = Bombadil::from_settings(Mode::Gpg)?;
 
-        if let Some(profiles) = profiles {
-            bombadil.enable_profiles(profiles)?;
+        let profiles: Vec<String> = match profiles {
+            Some(profiles) => profiles.into_iter().map(ToString::to_string).collect(),
+            None => bombadil.detect_profiles()?,
+        };
+
+        if let Some(unknown) = profiles
+            .iter()
+            .find(|profile| !bombadil.profiles.contains_key(*profile))
+        {
+            return Err(anyhow!("Unknown profile `{unknown}`"));
         }
 
+        bombadil.enable_profiles(profiles.iter().map(String::as_str).collect())?;
         bombadil.install(force)?;
 
         Ok(())
     }
 
+    // Profiles from the host rules matching this machine, or prompted for if none match
+    fn detect_profiles(&self) -> Result<Vec<String>> {
+        if let Some((host, profiles)) = hostname().and_then(|host| self.hosts.get_key_value(&host))
+        {
+            println!("Enabling profiles {:?} for host {}", profiles, host.green());
+            return Ok(profiles.clone());
+        }
+
+        if self.profiles.is_empty() || !io::stdin().is_terminal() {
+            return Ok(vec![]);
+        }
+
+        let mut available: Vec<String> = self.profiles.keys().cloned().collect();
+        available.sort();
+
+        println!("Available profiles:");
+        for (idx, profile) in available.iter().enumerate() {
+            println!("  {}) {}", idx + 1, profile);
+        }
+        println!("Profiles to enable (comma separated names or numbers, leave empty for none):");
+
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        parse_profile_selection(&input, &available)
+    }
+
     /// Symlink `bombadil.toml` to `$XDG_CONFIG/bombadil.toml` so we can later read it from there.
     pub fn link_self_config(dotfiles_path: Option<PathBuf>) -> Result<()> {
         // Get the provided path and attempt to resolve 'bombadil.toml' if it's a directory
@@ -144,88 +309,37 @@ impl Bombadil {
     /// 2. If any previous state is found in `.dot/previous_state.toml`, remove the existing symlinks
     /// 3. Clean existing rendered dotfiles templates in `.dot`
     /// 4. Copy and symlink dotfiles according to the current `$XDG_CONFIG/bombadil.toml` configuration
-    /// 5. Run post install hooks
+    /// 5. Run post install hooks, then `on_first_install` hooks if no previous state exists
+    ///    or `on_profile_change` hooks if the enabled profiles differ from the previous state
     /// 6. Write the current state to `.dot/previous_state.toml`
     pub fn install(&mut self, force: bool) -> Result<()> {
-        self.check_dotfile_dir()?;
+        // Get previous state if any
+        let absolute_path_to_dot = &self.dotfiles_absolute_path()?;
+        let previous_state = BombadilState::read(absolute_path_to_dot.to_owned());
 
-        self.prehooks.iter().map(Hook::run).for_each(|result| {
-            if let Err(err) = result {
-                eprintln!("{}", err);
+        let lifecycle_hooks = match &previous_state {
+            Err(_) => self.on_first_install.as_slice(),
+            Ok(state) if state.profiles_changed(&self.profile_enabled) => {
+                self.on_profile_change.as_slice()
             }
-        });
-
-        let dot_copy_dir = &self.path.join(".dots");
-
-        // Render current settings and create symlinks
-        fs::create_dir_all(dot_copy_dir)?;
-        let mut created = vec![];
-        let mut ignored = vec![];
-        let mut updated = vec![];
-        let mut direct = vec![];
-        let mut errored = vec![];
-
-        if self.vars.has_secrets() {
-            let decrypted = self.vars.get_secrets()?;
-            self.vars.with_secrets(decrypted);
-        }
-
-        let profiles_values = serde_json::to_value(&self.profile_enabled)?;
-        let mut profiles_context = tera::Map::new();
-        profiles_context.insert("profiles".to_string(), profiles_values);
-
-        self.vars.extend(Variables {
-            inner: Value::Object(profiles_context),
-        });
+            Ok(_) => &[],
+        };
 
-        for (key, dot) in self.dots.iter() {
-            match dot.install(&self.vars, self.get_auto_ignored_files(key)) {
-                Err(err) => errored.push((dot.source.clone(), err)),
-                Ok(linked) => {
-                    match linked {
-                        LinkResult::Updated { .. } => updated.push(linked),
-                        LinkResult::Created { .. } => created.push(linked),
-                        LinkResult::Ignored { .. } => ignored.push(linked),
-                        LinkResult::Direct { .. } => {
-                            direct.push(linked);
-                            dot.symlink_direct(force)?;
-                            continue;
-                        }
-                        LinkResult::Unchanged { .. } => {
-                            // Ignoring those for now
-                            // maybe we want to add them when implementing verbose mode
-                        }
-                    }
-                    dot.symlink(force)?;
-                }
-            }
-        }
+        let linked = self.link(None, force, lifecycle_hooks)?;
 
         let mut stdout = io::stdout();
 
-        links::write(created, &mut stdout, "Created")?;
-        links::write(updated, &mut stdout, "Updated")?;
-        links::write(ignored, &mut stdout, "Ignored")?;
-        links::write(direct, &mut stdout, "Direct")?;
-        links::write_errors(errored, &mut stdout)?;
-
-        // Run post install hooks
-        self.posthooks.iter().map(Hook::run).for_each(|result| {
-            if let Err(err) = result {
-                eprintln!("{}", err);
-            }
-        });
-
-        // Dump current settings
-        let absolute_path_to_dot = &self.dotfiles_absolute_path()?;
-
-        // Get previous state if any and remove symlinks
-        let previous_state = BombadilState::read(absolute_path_to_dot.to_owned());
-        let new_state = BombadilState::from(self);
+        // Remove symlinks from previous state
+        let mut new_state = BombadilState::from(self);
+        new_state.secret_renders = linked.secret_renders;
 
         let mut deletions = vec![];
         match previous_state {
             Ok(previous_state) => {
+                // Units dropped from the settings are stopped before their unit files are removed
+                let removed_units = previous_state.removed_units(&new_state);
+                systemd::disable(&removed_units);
+
                 let diff = previous_state.symlinks.difference(&new_state.symlinks);
                 for orphan in diff {
                     let path = orphan.to_string_lossy();
@@ -246,6 +360,10 @@ impl Bombadil {
                 }
 
                 links::write_deletion(deletions, &mut stdout)?;
+
+                if !removed_units.is_empty() {
+                    systemd::daemon_reload();
+                }
             }
             Err(err) => {
                 println!("No previous state: {err}")
@@ -254,19 +372,301 @@ impl Bombadil {
 
         new_state.write()?;
 
+        // Symlinks are tracked, an aborting posthook can now fail the installation
+        linked.error.map_or(Ok(()), Err)
+    }
+
+    /// Render and symlink only the given dot entries, used to re-render dots affected by a change.
+    /// Unlike [`Bombadil::install`] the previous state is left untouched.
+    pub fn install_dots(&mut self, dot_keys: &HashSet<String>, force: bool) -> Result<()> {
+        self.link(Some(dot_keys), force, &[])
+            .and_then(|linked| linked.error.map_or(Ok(()), Err))
+    }
+
+    // Run hooks, render and symlink dots, either all of them or the one matching `dot_keys`.
+    // `lifecycle_hooks` run last, after the post install hooks.
+    // Returns the rendered files holding decrypted secrets, along with the error of any step
+    // failing once dots are rendered.
+    fn link(
+        &self,
+        dot_keys: Option<&HashSet<String>>,
+        force: bool,
+        lifecycle_hooks: &[Hook],
+    ) -> Result<Linked> {
+        self.check_dotfile_dir()?;
+
+        let collisions = paths::target_collisions(&self.dots);
+        if !collisions.is_empty() {
+            let collisions: Vec<String> = collisions.iter().map(ToString::to_string).collect();
+            return Err(anyhow!(
+                "Conflicting dot targets :\n{}",
+                collisions.join("\n")
+            ));
+        }
+
+        self.sync_external_dots(dot_keys, false)?;
+
+        let vars = self.template_vars(true)?;
+
+        let log_dir = self.path.join(".dots").join("logs");
+        let mut hook_results = hook::run_all(&self.prehooks, &vars, Some(&log_dir))?;
+
+        let dot_copy_dir = &self.path.join(".dots");
+
+        // Render current settings and create symlinks
+        fs::create_dir_all(dot_copy_dir)?;
+        let mut created = vec![];
+        let mut ignored = vec![];
+        let mut updated = vec![];
+        let mut direct = vec![];
+        let mut errored = vec![];
+        let secret_renders = SecretRenders::new(
+            self.secrets
+                .runtime_dir
+                .then(secrets::runtime_dir)
+                .transpose()?,
+            self.secrets.age_identity.clone(),
+        );
+
+        // Read systemd units before rendering, to only reload the ones that changed
+        let unit_snapshots: HashMap<&String, UnitSnapshot> = self
+            .dots
+            .iter()
+            .filter(|(key, dot)| {
+                dot.systemd.is_some() && dot_keys.is_none_or(|keys| keys.contains(*key))
+            })
+            .map(|(key, dot)| (key, UnitSnapshot::take(dot)))
+            .collect();
+
+        // Render dots in parallel, symlinks are created sequentially afterward
+        let mut rendered: Vec<(&String, &Dot, Result<LinkResult>)> =
+            self.render_pool()?.install(|| {
+                self.dots
+                    .par_iter()
+                    .filter(|(key, _)| dot_keys.is_none_or(|keys| keys.contains(*key)))
+                    .map(|(key, dot)| {
+                        let result =
+                            dot.install(&vars, self.get_auto_ignored_files(key), &secret_renders);
+                        (key, dot, result)
+                    })
+                    .collect()
+            });
+
+        rendered.sort_by_key(|(key, ..)| *key);
+
+        // Rendered secrets are returned to be tracked even when linking fails afterward,
+        // `.dots/` is ignored before symlinks, systemd units and hooks get a chance to fail
+        let secret_renders = secret_renders.into_paths();
+        let symlink_rendered = || -> Result<()> {
+            if !secret_renders.is_empty() {
+                self.ignore_dot_copies()?;
+            }
+
+            let mut dot_posthooks = vec![];
+
+            let mut changed_units = vec![];
+
+            for (key, dot, result) in rendered {
+                match result {
+                    Err(err) => errored.push((dot.source.clone(), err)),
+                    Ok(linked) => {
+                        // Dot hooks only run when the dot actually changed
+                        if matches!(
+                            linked,
+                            LinkResult::Created { .. } | LinkResult::Updated { .. }
+                        ) {
+                            dot_posthooks.extend(
+                                dot.posthooks
+                                    .iter()
+                                    .cloned()
+                                    .map(|hook| hook.with_default_cwd(&self.path)),
+                            );
+                        }
+
+                        let is_direct = matches!(linked, LinkResult::Direct { .. });
+                        match linked {
+                            LinkResult::Updated { .. } => updated.push(linked),
+                            LinkResult::Created { .. } => created.push(linked),
+                            LinkResult::Ignored { .. } => ignored.push(linked),
+                            LinkResult::Direct { .. } => {
+                                direct.push(linked);
+                                dot.symlink_direct(force)?;
+                            }
+                            LinkResult::Unchanged { .. } => {
+                                // Ignoring those for now
+                                // maybe we want to add them when implementing verbose mode
+                            }
+                        }
+
+                        if !is_direct {
+                            dot.symlink(force)?;
+                        }
+                    }
+                }
+
+                if let (Some(options), Some(before)) = (dot.systemd, unit_snapshots.get(key)) {
+                    let units = UnitSnapshot::take(dot).changed_since(before);
+                    changed_units.extend(units.into_iter().map(|unit| (unit, options)));
+                }
+            }
+
+            let mut stdout = io::stdout();
+
+            links::write(created, &mut stdout, "Created")?;
+            links::write(updated, &mut stdout, "Updated")?;
+            links::write(ignored, &mut stdout, "Ignored")?;
+            links::write(direct, &mut stdout, "Direct")?;
+            links::write_errors(errored, &mut stdout)?;
+
+            systemd::apply(&changed_units);
+
+            // Run post install hooks, starting with the ones attached to updated dots
+            let posthooks = dot_posthooks
+                .iter()
+                .chain(self.posthooks.iter())
+                .chain(lifecycle_hooks.iter());
+            let hook_error = hook::run_all(posthooks, &vars, Some(&log_dir))
+                .map(|results| hook_results.extend(results))
+                .err();
+            display::hooks::write(&hook_results, &mut stdout)?;
+
+            hook_error.map_or(Ok(()), Err)
+        };
+
+        let error = symlink_rendered().err();
+        Ok(Linked {
+            secret_renders,
+            error,
+        })
+    }
+
+    // The thread pool rendering dots, built once and reused by every install and watch event
+    fn render_pool(&self) -> Result<&rayon::ThreadPool> {
+        if let Some(pool) = self.render_pool.get() {
+            return Ok(pool);
+        }
+
+        // `0` lets rayon use one thread per CPU
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.jobs.unwrap_or_default())
+            .build()?;
+
+        Ok(self.render_pool.get_or_init(|| Arc::new(pool)))
+    }
+
+    // Add `.dots/` to the dotfiles repository .gitignore, so rendered secrets are never committed
+    fn ignore_dot_copies(&self) -> Result<()> {
+        if !self.path.join(".git").exists() {
+            return Ok(());
+        }
+
+        let gitignore = self.path.join(".gitignore");
+        let content = fs::read_to_string(&gitignore).unwrap_or_default();
+        let ignored = content
+            .lines()
+            .map(str::trim)
+            .any(|line| matches!(line, ".dots" | ".dots/" | "/.dots" | "/.dots/"));
+
+        if !ignored {
+            let separator = if content.is_empty() || content.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+
+            fs::write(&gitignore, format!("{content}{separator}.dots/\n"))?;
+            println!(
+                "{} {:?}",
+                "Rendered secrets: added `.dots/` to".yellow(),
+                gitignore
+            );
+        }
+
+        Ok(())
+    }
+
+    // Clone the repositories of external dots, fetching them again only when `update` is set
+    // or their revision changed
+    fn sync_external_dots(&self, dot_keys: Option<&HashSet<String>>, update: bool) -> Result<()> {
+        let mut synced = HashSet::new();
+        for (key, dot) in &self.dots {
+            if dot_keys.is_some_and(|keys| !keys.contains(key)) {
+                continue;
+            }
+
+            let (Some(url), Some(path)) = (&dot.git, dot.external_repository()) else {
+                continue;
+            };
+
+            // Several dots can be taken from the same repository
+            if synced.insert(path.clone()) {
+                git::sync_external(url, dot.rev.as_deref(), &path, update, &self.git)
+                    .map_err(|err| anyhow!("Failed to sync external dot `{key}` : {err}"))?;
+            }
+        }
+
         Ok(())
     }
 
-    /// Unlink dotfiles according to previous state
-    pub fn uninstall(&self) -> Result<()> {
+    // Template context shared by dots and hooks: variables, optionally decrypted secrets
+    // and enabled profiles
+    fn template_vars(&self, decrypt_secrets: bool) -> Result<Variables> {
+        let mut vars = self.vars.clone();
+        if decrypt_secrets && vars.has_secrets() {
+            let decrypted = vars.get_secrets()?;
+            vars.with_secrets(decrypted);
+        }
+
+        let profiles_values = serde_json::to_value(&self.profile_enabled)?;
+        let mut profiles_context = tera::Map::new();
+        profiles_context.insert("profiles".to_string(), profiles_values);
+
+        vars.extend(Variables {
+            inner: Value::Object(profiles_context),
+        });
+
+        Ok(vars)
+    }
+
+    /// Unlink dotfiles according to previous state, running `pre_unlink` and `post_unlink` hooks
+    /// around symlinks removal. If no profile was explicitly enabled, the ones enabled during
+    /// the last install are used.
+    pub fn uninstall(&mut self) -> Result<()> {
         let mut success_paths: Vec<&PathBuf> = Vec::new();
         let mut error_paths: Vec<&anyhow::Error> = Vec::new();
 
-        // Remove symlink from previous state
         let path = self.dotfiles_absolute_path()?;
         let previous_state = BombadilState::read(path)?;
+
+        if self.profile_enabled.is_empty() {
+            self.enable_previous_profiles(&previous_state)?;
+        }
+
+        // Secrets are not decrypted when unlinking
+        let vars = self.template_vars(false)?;
+        let log_dir = self.path.join(".dots").join("logs");
+        let mut hook_results = hook::run_all(&self.pre_unlink, &vars, Some(&log_dir))?;
+
+        // Stop the systemd units managed by the last install before their unit files disappear,
+        // along with the ones of the current settings in case the state predates unit tracking
+        let units: Vec<String> = self
+            .dots
+            .values()
+            .filter(|dot| dot.systemd.is_some())
+            .flat_map(|dot| UnitSnapshot::take(dot).units())
+            .chain(previous_state.units.iter().cloned())
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect();
+        systemd::disable(&units);
+
+        // Remove symlink from previous state
         let remove_result = previous_state.remove_targets();
 
+        if !units.is_empty() {
+            systemd::daemon_reload();
+        }
+
         remove_result
             .iter()
             .for_each(|remove_result| match remove_result {
@@ -290,91 +690,394 @@ impl Bombadil {
             });
         }
 
+        // Rendered secrets are useless once unlinked
+        for path in previous_state.remove_secret_renders() {
+            println!("{} {:?}", "Removed rendered secrets:".green(), path);
+        }
+
+        hook_results.extend(hook::run_all(&self.post_unlink, &vars, Some(&log_dir))?);
+        display::hooks::write(&hook_results, &mut io::stdout())?;
+
         Ok(())
     }
 
-    /// Watch dotfiles and automatically run link on changes
-    pub async fn watch(profiles: Vec<String>, force: bool) -> Result<()> {
+    /// Declared packages, from the default and enabled profiles, missing on this system
+    pub fn missing_packages(&self) -> Result<Vec<String>> {
+        self.packages.missing()
+    }
+
+    /// Install missing packages with the configured installer, returning the installed ones
+    pub fn install_packages(&self) -> Result<Vec<String>> {
+        let missing = self.missing_packages()?;
+        self.packages.install(&missing)?;
+        Ok(missing)
+    }
+
+    // Enable the profiles persisted by the last install
+    fn enable_previous_profiles(&mut self, previous_state: &BombadilState) -> Result<()> {
+        // Extra profiles are stored along the one enabling them, skip them
+        // as well as profiles removed from the settings since the last install
+        let extra_profiles: HashSet<&String> = previous_state
+            .profiles
+            .iter()
+            .filter_map(|key| self.profiles.get(key))
+            .flat_map(|profile| profile.extra_profiles.iter())
+            .collect();
+        let profiles = previous_state
+            .profiles
+            .iter()
+            .filter(|key| self.profiles.contains_key(*key) && !extra_profiles.contains(key))
+            .map(String::as_str)
+            .collect();
+        self.enable_profiles(profiles)
+    }
+
+    /// Fast-forward the dotfiles repository to its upstream, then install with the profiles
+    /// enabled during the last install. Pulling with uncommitted changes requires `force`.
+    pub fn pull(force: bool) -> Result<()> {
+        let settings = Settings::get()?;
+        let path = settings.get_dotfiles_path()?;
+        let summary = git::pull(&path, force, &settings.settings.git)?;
+
+        let mut stdout = io::stdout();
+        if summary.commits.is_empty() {
+            println!("Already up to date");
+        } else {
+            writeln!(stdout, "{}", "[Incoming commits]".bold().yellow())?;
+            for commit in &summary.commits {
+                writeln!(stdout, "{commit}")?;
+            }
+            writeln!(stdout)?;
+        }
+
+        // Settings might have changed, load them again
         let mut bombadil = Bombadil::from_settings(Mode::Gpg)?;
-        bombadil.enable_profiles(profiles.iter().map(String::as_str).collect())?;
+        if let Ok(previous_state) = BombadilState::read(bombadil.dotfiles_absolute_path()?) {
+            bombadil.enable_previous_profiles(&previous_state)?;
+        }
 
-        let dotfiles_path = &bombadil.dotfiles_absolute_path()?;
-
-        // Ignore stuff like .git dirs
-        let (ignore_files, _) = ignore_files::from_origin(dotfiles_path.as_path()).await;
-        let ignore_filter = IgnoreFilter::new(dotfiles_path, &ignore_files).await?;
-
-        let watchexec = Watchexec::new(move |mut action| {
-            let mut b = Bombadil::from_settings(Mode::Gpg).expect("Failed to get settings");
-            b.enable_profiles(profiles.iter().map(String::as_str).collect())
-                .expect("Failed to enable profiles");
-            {
-                for event in action.events.iter() {
-                    // Select only relevant events (creations, modifications, deletions)
-                    if event.tags.iter().any(|t| {
-                        matches!(t, &Tag::FileEventKind(FileEventKind::Create(_)))
-                            || matches!(t, &Tag::FileEventKind(FileEventKind::Modify(_)))
-                            || matches!(t, &Tag::FileEventKind(FileEventKind::Remove(_)))
-                    }) {
-                        println!("{}", "Detected changes, re-linking dots ...".green());
-                        // Finally, install the dots like usual
-                        b.install(force)
-                            .map_err(|e| RuntimeError::Handler {
-                                ctx: "bombadil install",
-                                err: e.to_string(),
-                            })
-                            .expect("failed to run install");
-                        break;
-                    }
-                }
+        // External dots may have been updated upstream even if the dotfiles did not change
+        if summary.commits.is_empty() && bombadil.dots.values().all(|dot| dot.git.is_none()) {
+            return Ok(());
+        }
+
+        bombadil.sync_external_dots(None, true)?;
+
+        let mut changed_dots: Vec<&String> = bombadil
+            .dots
+            .iter()
+            .filter(|(_, dot)| dot.git.is_none())
+            .filter(|(_, dot)| {
+                let source = bombadil.path.join(&dot.source);
+                let source = source.canonicalize().unwrap_or(source);
+                summary
+                    .changed_files
+                    .iter()
+                    .any(|changed| changed.starts_with(&source))
+            })
+            .map(|(key, _)| key)
+            .collect();
+        changed_dots.sort();
+
+        if !changed_dots.is_empty() {
+            writeln!(stdout, "{}", "[Changed dots]".bold().yellow())?;
+            for dot in changed_dots {
+                writeln!(stdout, "{dot}")?;
+            }
+            writeln!(stdout)?;
+        }
 
-                if action.signals().next().is_some() {
-                    eprintln!("[Quitting...]");
-                    action.quit();
+        bombadil.install(false)
+    }
+
+    /// Print the dots with uncommitted source changes, followed by the other uncommitted files
+    pub fn git_status(&self, out: &mut impl Write) -> Result<()> {
+        let changes = self.uncommitted_changes()?;
+        let (dots, others) = self.group_by_dot(&changes);
+
+        if changes.is_empty() {
+            writeln!(out, "Nothing to commit, dotfiles are clean")?;
+            return Ok(());
+        }
+
+        if !dots.is_empty() {
+            writeln!(out, "{}", "[Uncommitted dots]".bold().yellow())?;
+            for (key, files) in dots {
+                writeln!(out, "{}", key.green())?;
+                for file in files {
+                    writeln!(out, "\t{}", self.relative_path(file).display())?;
                 }
+            }
+            writeln!(out)?;
+        }
 
-                action
+        if !others.is_empty() {
+            writeln!(out, "{}", "[Other changes]".bold().yellow())?;
+            for file in others {
+                writeln!(out, "{}", self.relative_path(file).display())?;
             }
-        })?;
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
 
-        watchexec
-            .config
-            .filterer(IgnoreFilterer(ignore_filter))
-            .throttle(Duration::from_secs(1))
-            .file_watcher(Watcher::Native)
-            .pathset([dotfiles_path.as_path()]);
+    /// Commit every uncommitted change in the dotfiles directory, the default message
+    /// lists the touched dots
+    pub fn git_commit(&self, message: Option<&str>) -> Result<()> {
+        let changes = self.uncommitted_changes()?;
+        if changes.is_empty() {
+            println!("Nothing to commit, dotfiles are clean");
+            return Ok(());
+        }
+
+        let message = match message {
+            Some(message) => message.to_string(),
+            None => {
+                let (dots, _) = self.group_by_dot(&changes);
+                if dots.is_empty() {
+                    "Update dotfiles".to_string()
+                } else {
+                    let keys: Vec<&str> = dots.keys().map(|key| key.as_str()).collect();
+                    format!("Update dots: {}", keys.join(", "))
+                }
+            }
+        };
 
-        watchexec.main().await??;
+        let oid = git::commit(&self.path, &changes, &message)?;
+        println!("{} {}", oid.to_string()[..7].yellow(), message);
         Ok(())
     }
 
-    /// Add a gpg secret encrypted variable to the target variable file
+    /// Push the dotfiles repository current branch
+    pub fn git_push(&self) -> Result<()> {
+        git::push(&self.path, &self.git)
+    }
+
+    // Uncommitted files in the dotfiles directory, rendered dots excluded
+    fn uncommitted_changes(&self) -> Result<Vec<PathBuf>> {
+        let path = self.path.canonicalize()?;
+        let dot_copy_dir = path.join(".dots");
+        Ok(git::changed_files(&path)?
+            .into_iter()
+            .filter(|file| file.starts_with(&path) && !file.starts_with(&dot_copy_dir))
+            .collect())
+    }
+
+    // Group changed files by the dot they belong to, files outside any dot are returned aside
+    fn group_by_dot<'a>(
+        &'a self,
+        files: &'a [PathBuf],
+    ) -> (BTreeMap<&'a String, Vec<&'a PathBuf>>, Vec<&'a PathBuf>) {
+        let path = self
+            .path
+            .canonicalize()
+            .unwrap_or_else(|_| self.path.clone());
+        let mut dots: BTreeMap<&String, Vec<&PathBuf>> = BTreeMap::new();
+        let mut others = vec![];
+
+        for file in files {
+            let mut matched = false;
+            for (key, dot) in self.dots.iter().filter(|(_, dot)| dot.git.is_none()) {
+                if file.starts_with(path.join(&dot.source)) {
+                    dots.entry(key).or_default().push(file);
+                    matched = true;
+                }
+            }
+
+            if !matched {
+                others.push(file);
+            }
+        }
+
+        (dots, others)
+    }
+
+    fn relative_path<'a>(&self, file: &'a Path) -> &'a Path {
+        let path = self
+            .path
+            .canonicalize()
+            .unwrap_or_else(|_| self.path.clone());
+        file.strip_prefix(path).unwrap_or(file)
+    }
+
+    /// Add an encrypted secret variable to the target variable file, using the file
+    /// `secrets_backend` or gpg by default
     pub fn add_secret<S: AsRef<Path> + ?Sized>(
         &self,
         key: &str,
         value: &str,
         var_file: &S,
     ) -> Result<()> {
-        if let Some(gpg) = &self.gpg {
-            gpg.push_secret(key, value, var_file)
+        let var_file = var_file.as_ref();
+        let vars = Variables::read(var_file)?;
+        if let Some(backend) = secrets::file_backend(&vars.inner)? {
+            secrets::push_secret(
+                backend.backend()?.as_ref(),
+                key,
+                value,
+                var_file,
+                self.secrets.blob,
+            )
+        } else if let Some(gpg) = &self.gpg {
+            gpg.push_secret(key, value, var_file, self.secrets.blob)
         } else {
             Err(anyhow!("No gpg_user_id in bombadil settings"))
         }
     }
 
-    /// Enable a dotfile profile by merging its settings with the default profile
-    pub fn enable_profiles(&mut self, profile_keys: Vec<&str>) -> Result<()> {
-        if profile_keys.is_empty() {
-            return Ok(());
+    /// List the secrets of a vars file, or of every vars file in the settings
+    pub fn list_secrets(&self, var_file: Option<&Path>, out: &mut impl Write) -> Result<()> {
+        for path in self.secret_var_files(var_file) {
+            let mut secrets = VarFile::open(&path)?.list()?;
+            secrets.sort_by(|a, b| a.key.cmp(&b.key));
+
+            writeln!(out, "{}", format!("[{}]", path.display()).bold().yellow())?;
+            for secret in secrets {
+                let blob = if secret.blob { " (blob)" } else { "" };
+                writeln!(out, "{}\t{}{}", secret.key.green(), secret.backend, blob)?;
+            }
+            writeln!(out)?;
         }
 
-        self.profile_enabled = profile_keys.iter().map(ToString::to_string).collect();
+        Ok(())
+    }
 
-        let mut profiles: Vec<Profile> = profile_keys
-            .into_iter()
-            // unwrap here is safe cause allowed profile keys are checked by clap
-            .map(|profile_key| self.profiles.get(profile_key).unwrap())
-            .cloned()
+    /// Remove a secret from a vars file
+    pub fn remove_secret(&self, key: &str, var_file: &Path) -> Result<()> {
+        let mut file = VarFile::open(var_file)?;
+        file.remove(key)?;
+        file.save()?;
+        println!("Removed {key}");
+        Ok(())
+    }
+
+    /// Decrypt a secret into `$EDITOR` and encrypt the edited value again
+    pub fn edit_secret(&self, key: &str, var_file: &Path) -> Result<()> {
+        let mut file = VarFile::open(var_file)?;
+        let plaintext = file.get(key)?;
+        let edited = secrets::edit_in_editor(&plaintext)?;
+
+        if edited == plaintext {
+            println!("{key} is unchanged");
+            return Ok(());
+        }
+
+        let backend = file.backend_settings()?.backend()?;
+        file.set(backend.as_ref(), key, &edited, false)?;
+        file.save()?;
+        println!("Updated {key}");
+        Ok(())
+    }
+
+    /// Encrypt the secrets of a vars file, or of every vars file in the settings, for new recipients
+    pub fn reencrypt_secrets(&self, recipients: &[String], var_file: Option<&Path>) -> Result<()> {
+        for path in self.secret_var_files(var_file) {
+            let mut file = VarFile::open(&path)?;
+            let reencrypted = file.reencrypt(recipients)?;
+            file.save()?;
+            println!(
+                "Re-encrypted {} secrets in {}",
+                reencrypted.len(),
+                file.path().display()
+            );
+
+            if !file.declares_backend() && !reencrypted.is_empty() {
+                println!("Update `gpg_user_id` and `gpg_recipients` in your bombadil.toml");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Print who can decrypt the secrets of a vars file, or of every vars file in the settings
+    pub fn secret_recipients(&self, var_file: Option<&Path>, out: &mut impl Write) -> Result<()> {
+        for path in self.secret_var_files(var_file) {
+            let recipients = VarFile::open(&path)?.recipients()?;
+
+            writeln!(out, "{}", format!("[{}]", path.display()).bold().yellow())?;
+            for (key_id, count) in &recipients.gpg {
+                let user_id = Gpg::user_id_of(key_id).unwrap_or_else(|| "unknown key".to_string());
+                if *count == recipients.gpg_secrets {
+                    writeln!(out, "{}\t{}", key_id.green(), user_id)?;
+                } else {
+                    let partial = format!("({}/{} secrets)", count, recipients.gpg_secrets);
+                    writeln!(out, "{}\t{} {}", key_id.green(), user_id, partial.red())?;
+                }
+            }
+
+            for recipient in &recipients.age {
+                writeln!(out, "{}\tage recipient", recipient.green())?;
+            }
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt a whole file with gpg, or age when `age` is set, to use it as a dot source.
+    /// The encrypted file defaults to the dotfiles directory, named after the file with
+    /// a `.gpg` or `.age` extension.
+    pub fn encrypt_file(&self, file: &Path, output: Option<&Path>, age: bool) -> Result<PathBuf> {
+        let extension = if age { "age" } else { "gpg" };
+        let output = match output {
+            Some(output) => output.to_path_buf(),
+            None => {
+                let name = file
+                    .file_name()
+                    .ok_or_else(|| anyhow!("{:?} is not a file", file))?
+                    .to_string_lossy();
+                self.path
+                    .join(format!("{}.{}", name.trim_start_matches('.'), extension))
+            }
+        };
+
+        if output.extension().and_then(OsStr::to_str) != Some(extension) {
+            return Err(anyhow!(
+                "{:?} must have a `.{}` extension to be decrypted on install",
+                output,
+                extension
+            ));
+        }
+
+        let encrypted = if age {
+            secrets::Age::new(None, self.secrets.age_recipients.clone()).encrypt_file(file)?
+        } else if let Some(gpg) = &self.gpg {
+            gpg.encrypt_file(file)?
+        } else {
+            return Err(anyhow!("No gpg_user_id in bombadil settings"));
+        };
+
+        fs::write(&output, encrypted)?;
+        Ok(output)
+    }
+
+    // The given var file, or the var files of the settings
+    fn secret_var_files(&self, var_file: Option<&Path>) -> Vec<PathBuf> {
+        match var_file {
+            Some(var_file) => vec![var_file.to_path_buf()],
+            None => self
+                .var_paths
+                .iter()
+                .map(|path| self.path.join(path))
+                .filter(|path| path.exists())
+                .collect(),
+        }
+    }
+
+    /// Enable a dotfile profile by merging its settings with the default profile
+    pub fn enable_profiles(&mut self, profile_keys: Vec<&str>) -> Result<()> {
+        if profile_keys.is_empty() {
+            return Ok(());
+        }
+
+        self.profile_enabled = profile_keys.iter().map(ToString::to_string).collect();
+
+        let mut profiles: Vec<Profile> = profile_keys
+            .into_iter()
+            // unwrap here is safe cause allowed profile keys are checked by clap
+            .map(|profile_key| self.profiles.get(profile_key).unwrap())
+            .cloned()
             .collect();
 
         let sub_profiles: Vec<(String, Profile)> = profiles
@@ -397,8 +1100,9 @@ impl Bombadil {
             profiles.push(profile);
         }
 
-        // Merge profile dots
-        for profile in profiles.iter() {
+        // Merge profile dots, profiles are in the same order as their names
+        let names = self.profile_enabled.clone();
+        for (name, profile) in names.iter().zip(profiles.iter()) {
             profile.dots.iter().for_each(|(key, dot_override)| {
                 // Dot exist let's override
                 if let Some(dot) = self.dots.get_mut(key) {
@@ -410,6 +1114,10 @@ impl Bombadil {
                         dot.target.clone_from(target);
                     }
 
+                    if dot_override.source.is_some() || dot_override.target.is_some() {
+                        dot.origin = DotOrigin::Profile(name.clone());
+                    }
+
                     if let Some(vars) = &dot_override.vars {
                         dot.vars.clone_from(vars);
                     }
@@ -418,31 +1126,52 @@ impl Bombadil {
                         dot.direct.clone_from(templating);
                     }
 
-                    if let (None, None, None, None) = (
+                    if let Some(posthooks) = &dot_override.posthooks {
+                        dot.posthooks.clone_from(posthooks);
+                    }
+
+                    if let Some(systemd) = dot_override.systemd {
+                        dot.systemd = Some(systemd);
+                    }
+
+                    if let Some(git) = &dot_override.git {
+                        dot.git = Some(git.clone());
+                    }
+
+                    if let Some(rev) = &dot_override.rev {
+                        dot.rev = Some(rev.clone());
+                    }
+
+                    if let (None, None, None, None, None, None, None, None) = (
                         &dot_override.source,
                         &dot_override.target,
                         &dot_override.vars,
                         &dot_override.direct,
+                        &dot_override.posthooks,
+                        &dot_override.systemd,
+                        &dot_override.git,
+                        &dot_override.rev,
                     ) {
                         let warning = format!(
-                            "Skipping {}, no `source`, `target`, `vars`, or `templating` to override",
+                            "Skipping {}, no `source`, `target`, `vars`, `templating`, `posthooks`, `systemd`, `git` or `rev` to override",
                             key
                         )
                         .yellow();
                         eprintln!("{}", warning);
                     }
                     // Nothing to override, let's create a new dot entry
-                } else if let (Some(source), Some(target)) =
-                    (&dot_override.source, &dot_override.target)
-                {
-                    let source = source.clone();
+                } else if let (Some(source), Some(target)) = (
+                    // External dots default to the repository root
+                    dot_override
+                        .source
+                        .clone()
+                        .or_else(|| dot_override.git.as_ref().map(|_| PathBuf::new())),
+                    &dot_override.target,
+                ) {
                     let target = target.clone();
                     let ignore = dot_override.ignore.clone();
-                    let direct = if dot_override.direct.is_some() {
-                        dot_override.direct.unwrap()
-                    } else {
-                        false
-                    };
+                    let direct = dot_override.direct.unwrap_or(false);
+                    let posthooks = dot_override.posthooks.clone().unwrap_or_default();
 
                     self.dots.insert(
                         key.to_string(),
@@ -452,10 +1181,15 @@ impl Bombadil {
                             ignore,
                             vars: Dot::default_vars(),
                             direct,
+                            posthooks,
+                            systemd: dot_override.systemd,
+                            git: dot_override.git.clone(),
+                            rev: dot_override.rev.clone(),
+                            origin: DotOrigin::Profile(name.clone()),
                         },
                     );
                 } else {
-                    if dot_override.source.is_none() {
+                    if dot_override.source.is_none() && dot_override.git.is_none() {
                         let warning = format!("`source` field missing for {}", key).yellow();
                         eprintln!("{}", warning);
                     }
@@ -470,28 +1204,45 @@ impl Bombadil {
             // Add profile vars
             let variables = Variables::from_paths(&self.path, &profile.vars)?;
             self.vars.extend(variables);
-            // Add Profile pre-hooks
-            let prehooks = profile
-                .prehooks
-                .iter()
-                .map(|command| command.as_ref())
-                .map(Hook::new)
-                .collect::<Vec<Hook>>();
-            self.prehooks.extend(prehooks);
+            self.var_paths.extend_from_slice(&profile.vars);
+            // Add profile packages
+            for package in &profile.packages {
+                if !self.packages.list.contains(package) {
+                    self.packages.list.push(package.clone());
+                }
+            }
 
-            // Add profile post-hooks
-            let posthooks = profile
-                .posthooks
-                .iter()
-                .map(|command| command.as_ref())
-                .map(Hook::new)
-                .collect::<Vec<Hook>>();
+            // Add profile hooks
+            let with_cwd = |hooks: &[Hook]| {
+                hooks
+                    .iter()
+                    .cloned()
+                    .map(|hook| hook.with_default_cwd(&self.path))
+                    .collect::<Vec<Hook>>()
+            };
+            let prehooks = with_cwd(&profile.prehooks);
+            let posthooks = with_cwd(&profile.posthooks);
+            let pre_unlink = with_cwd(&profile.pre_unlink);
+            let post_unlink = with_cwd(&profile.post_unlink);
+            let on_first_install = with_cwd(&profile.on_first_install);
+            let on_profile_change = with_cwd(&profile.on_profile_change);
+            self.prehooks.extend(prehooks);
             self.posthooks.extend(posthooks);
+            self.pre_unlink.extend(pre_unlink);
+            self.post_unlink.extend(post_unlink);
+            self.on_first_install.extend(on_first_install);
+            self.on_profile_change.extend(on_profile_change);
         }
 
         Ok(())
     }
 
+    /// Reload template variables from the global and enabled profiles var files
+    pub(crate) fn reload_vars(&mut self) -> Result<()> {
+        self.vars = Variables::from_paths(&self.path, &self.var_paths)?.with_os();
+        Ok(())
+    }
+
     fn check_dotfile_dir(&self) -> Result<()> {
         if !self.path.exists() {
             return Err(anyhow!(
@@ -517,40 +1268,64 @@ impl Bombadil {
     pub fn from_settings(mode: Mode) -> Result<Bombadil> {
         let config = Settings::get()?;
         let path = config.get_dotfiles_path()?;
+        let imports = config.import_paths();
 
         let gpg = match mode {
-            Mode::Gpg => config.gpg_user_id.map(|user_id| Gpg::new(&user_id)),
+            Mode::Gpg => config
+                .gpg_user_id
+                .map(|user_id| Gpg::new(&user_id).with_recipients(&config.gpg_recipients)),
             Mode::NoGpg => None,
         };
 
         // Resolve variables from path
-        let vars = Variables::from_paths(&path, &config.settings.vars)?.with_os();
+        let var_paths = config.settings.vars.clone();
+        let vars = Variables::from_paths(&path, &var_paths)?.with_os();
 
         // Resolve hooks from settings
-        let posthooks = config
-            .settings
-            .posthooks
-            .iter()
-            .map(|cmd| Hook::new(cmd))
-            .collect();
-
-        let prehooks = config
-            .settings
-            .prehooks
-            .iter()
-            .map(|cmd| Hook::new(cmd))
-            .collect();
+        let with_cwd = |hooks: Vec<Hook>| {
+            hooks
+                .into_iter()
+                .map(|hook| hook.with_default_cwd(&path))
+                .collect::<Vec<Hook>>()
+        };
+        let posthooks = with_cwd(config.settings.posthooks);
+        let prehooks = with_cwd(config.settings.prehooks);
+        let pre_unlink = with_cwd(config.settings.pre_unlink);
+        let post_unlink = with_cwd(config.settings.post_unlink);
+        let on_first_install = with_cwd(config.settings.on_first_install);
+        let on_profile_change = with_cwd(config.settings.on_profile_change);
 
         let dots = config.settings.dots;
+        let jobs = config.settings.jobs;
+        let watch = config.settings.watch;
+        let git = config.settings.git;
+        let secrets = config.settings.secrets;
+        let mut packages = config.settings.packages;
+        let mut seen = HashSet::new();
+        packages.list.retain(|package| seen.insert(package.clone()));
         let profiles = config.profiles;
+        let hosts = config.hosts;
 
         Ok(Self {
             path,
             dots,
             vars,
+            var_paths,
+            imports,
+            jobs,
+            render_pool: OnceLock::new(),
+            watch,
+            packages,
+            git,
+            secrets,
             prehooks,
             posthooks,
+            pre_unlink,
+            post_unlink,
+            on_first_install,
+            on_profile_change,
             profiles,
+            hosts,
             gpg,
             profile_enabled: vec![],
         })
@@ -606,213 +1381,955 @@ impl Bombadil {
 
                 writer.flush()?;
             }
-            MetadataType::Secrets => {
-                if no_color {
-                    let value = serde_json::to_vec_pretty(&json!({
-                        "secrets": &self.vars.get_secrets()?
-                    }))?;
-                    writer.write_all(&value)?;
-                } else {
-                    colored_json::write_colored_json(
-                        &json!({
-                            "secrets": &self.vars.get_secrets()?
-                        }),
-                        writer,
-                    )?;
-                };
+            MetadataType::Secrets => self.print_secrets(false, writer, no_color)?,
+        };
+
+        Ok(())
+    }
+
+    /// Pretty print secrets, their values are masked unless `reveal` is set.
+    /// Masked secrets are listed from the var files without decrypting them.
+    pub fn print_secrets(
+        &self,
+        reveal: bool,
+        writer: &mut impl Write,
+        no_color: bool,
+    ) -> Result<()> {
+        let secrets: serde_json::Map<String, Value> = if reveal {
+            self.vars.get_secrets()?
+        } else {
+            let mut secrets = serde_json::Map::new();
+            for path in self.secret_var_files(None) {
+                for secret in VarFile::open(&path)?.list()? {
+                    secrets.insert(secret.key, Value::String(SECRET_MASK.to_string()));
+                }
+            }
+            secrets
+        };
+
+        let secrets = json!({ "secrets": secrets });
+        if no_color {
+            let value = serde_json::to_vec_pretty(&secrets)?;
+            writer.write_all(&value)?;
+        } else {
+            colored_json::write_colored_json(&secrets, writer)?;
+        };
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn rows_to_writer(writer: &mut (impl Write + Sized), rows: Vec<String>) -> io::Result<()> {
+        if !rows.is_empty() {
+            writer.write_all(rows.join("\n").as_bytes())?;
+            writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn dotfiles_absolute_path(&self) -> Result<PathBuf> {
+        dirs::home_dir()
+            .ok_or_else(|| anyhow!("$HOME dir not found"))
+            .map(|path| path.join(&self.path))
+    }
+
+    fn get_auto_ignored_files(&self, dot_key: &str) -> Vec<PathBuf> {
+        let dot_origin = self.dots.get(dot_key);
+
+        let mut ignored: Vec<PathBuf> = self
+            .profiles
+            .values()
+            .filter_map(|profile| profile.dots.get(dot_key))
+            .filter(|dot| dot.vars.is_some())
+            .filter_map(|dot| dot.resolve_var_path(dot_origin))
+            .collect();
+
+        let _ = dot_origin.map(|dot| dot.resolve_var_path().map(|path| ignored.push(path)));
+
+        ignored
+    }
+}
+
+// Printed in place of decrypted values by `bombadil get secrets`
+const SECRET_MASK: &str = "********";
+
+pub enum MetadataType {
+    Dots,
+    PreHooks,
+    PostHooks,
+    Path,
+    Profiles,
+    Vars,
+    /// Secret values are masked, see [`Bombadil::print_secrets`] to reveal them
+    Secrets,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paths::unlink;
+    use crate::test_helpers::{commit_all, commit_file, setup, setup_home, setup_secret_backends};
+    use crate::Mode::NoGpg;
+    use cmd_lib::run_cmd;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::PermissionsExt;
+    use std::{env, fs};
+
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn self_link_works() {
+        let link = dirs::config_dir().unwrap().join(BOMBADIL_CONFIG);
+
+        assert_that!(link).exists();
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn install_single_file_works() -> Result<()> {
+        Bombadil::from_settings(NoGpg)?.install(false)?;
+
+        let target = fs::read_link(".config/template.css")?;
+        let expected = env::current_dir()?.join("dotfiles_simple/.dots/template.css");
+
+        assert_that!(target).is_equal_to(expected);
+
+        let target = std::fs::read_to_string(target)?;
+
+        assert_eq!(
+            target,
+            indoc! {
+                ".class {
+                    color: #de1f1f
+                }
+                "
+            }
+        );
+
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_direct"], before = setup("dotfiles_direct"))]
+    fn install_direct_file_works() -> Result<()> {
+        Bombadil::from_settings(NoGpg)?.install(false)?;
+
+        let target = fs::read_link(".config/file.txt")?;
+        let expected = env::current_dir()?.join("dotfiles_direct/file.txt");
+
+        assert_that!(target).is_equal_to(expected);
+
+        Ok(())
+    }
+
+    // Write a dot source of 20 directories holding 100 templates each
+    fn write_large_dot(source: &str) -> Result<()> {
+        for dir in 0..20 {
+            fs::create_dir_all(format!("{source}/dir_{dir}"))?;
+            for file in 0..100 {
+                fs::write(
+                    format!("{source}/dir_{dir}/file_{file}"),
+                    format!("{{{{ name }}}} {dir} {file}"),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_jobs"], before = setup("dotfiles_with_jobs"))]
+    fn install_large_directory_with_jobs() -> Result<()> {
+        // Arrange
+        write_large_dot("dotfiles_with_jobs/large")?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Act
+        bombadil.install(false)?;
+        let pool: *const rayon::ThreadPool = bombadil.render_pool()?;
+        bombadil.install(false)?;
+
+        // Assert
+        assert_that!(bombadil.render_pool()?.current_num_threads()).is_equal_to(2);
+        assert_that!(std::ptr::eq(pool, bombadil.render_pool()?)).is_true();
+        for dir in 0..20 {
+            let rendered = fs::read_dir(format!(".config/large/dir_{dir}"))?.count();
+            assert_that!(rendered).is_equal_to(100);
+        }
+        let content = fs::read_to_string(".config/large/dir_19/file_99")?;
+        assert_that!(content).is_equal_to("Tom Bombadil 19 99".to_string());
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_jobs"], before = setup("dotfiles_with_jobs"))]
+    #[ignore = "benchmark, run with `cargo test bench_install -- --ignored --nocapture`"]
+    fn bench_install_large_directory() -> Result<()> {
+        write_large_dot("dotfiles_with_jobs/large")?;
+
+        for jobs in [1, 2, 0] {
+            let mut bombadil = Bombadil::from_settings(NoGpg)?;
+            bombadil.jobs = Some(jobs);
+
+            let start = std::time::Instant::now();
+            bombadil.install(true)?;
+            println!("jobs = {jobs}: {:?}", start.elapsed());
+        }
+
+        Ok(())
+    }
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn force_install_single_file_works() -> Result<()> {
+        fs::write(".config/template.css", "foo")?;
+        Bombadil::from_settings(NoGpg)?.install(true)?;
+
+        let target = fs::read_link(".config/template.css")?;
+        let expected = env::current_dir()?.join("dotfiles_simple/.dots/template.css");
+
+        assert_that!(target).is_equal_to(expected);
+
+        let target = std::fs::read_to_string(target)?;
+
+        assert_eq!(
+            target,
+            indoc! {
+                ".class {
+                    color: #de1f1f
+                }
+                "
+            }
+        );
+
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_invalid_dot"], before = setup("dotfiles_invalid_dot"))]
+    fn install_should_fail_and_continue() -> Result<()> {
+        // Act
+        Bombadil::from_settings(NoGpg)?.install(false)?;
+
+        // Assert
+        assert_that!(PathBuf::from(".config/template.css")).exists();
+        assert_that!(PathBuf::from(".config/invalid")).does_not_exist();
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn uninstall_works() -> Result<()> {
+        Bombadil::link_self_config(Some(PathBuf::from("dotfiles_simple")))?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        bombadil.install(false)?;
+        assert_that!(PathBuf::from(".config/template.css")).exists();
+
+        bombadil.uninstall()?;
+        assert_that!(PathBuf::from(".config/template.css")).does_not_exist();
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn posthook_ok() -> Result<()> {
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Act
+        bombadil.install(false)?;
+
+        // Assert
+        assert_that!(PathBuf::from(".config/posthook/file").exists());
+
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn prehook_ok() -> Result<()> {
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Act
+        bombadil.install(false)?;
+
+        // Assert
+        assert_that!(PathBuf::from(".config/prehook_file")).exists();
+
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_hook_policies"], before = setup("dotfiles_with_hook_policies"))]
+    fn hook_runs_in_dotfiles_dir() -> Result<()> {
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Act
+        bombadil.install(false)?;
+
+        // Assert
+        let cwd = fs::read_to_string("hook_cwd")?;
+        let expected = env::current_dir()?.join("dotfiles_with_hook_policies");
+        assert_that!(PathBuf::from(cwd.trim())).is_equal_to(expected);
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_hook_policies"], before = setup("dotfiles_with_hook_policies"))]
+    fn aborting_prehook_stops_install() -> Result<()> {
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.enable_profiles(vec!["strict"])?;
+
+        // Act
+        let result = bombadil.install(false);
+
+        // Assert
+        assert_that!(result).is_err();
+        assert_that!(PathBuf::from(".config/template.css")).does_not_exist();
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_dot_hooks"], before = setup("dotfiles_with_dot_hooks"))]
+    fn dot_posthook_runs_only_on_change() -> Result<()> {
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Act
+        bombadil.install(false)?;
+        assert_that!(PathBuf::from("sway_reloaded")).exists();
+        fs::remove_file("sway_reloaded")?;
+
+        bombadil.install(false)?;
+        assert_that!(PathBuf::from("sway_reloaded")).does_not_exist();
+
+        fs::write(
+            "dotfiles_with_dot_hooks/sway/config",
+            "output * bg #ffffff solid_color",
+        )?;
+        bombadil.install(false)?;
+
+        // Assert
+        assert_that!(PathBuf::from("sway_reloaded")).exists();
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_lifecycle_hooks"], before = setup("dotfiles_with_lifecycle_hooks"))]
+    fn first_install_and_profile_change_hooks() -> Result<()> {
+        // Arrange
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Act
+        bombadil.install(false)?;
+        assert_that!(PathBuf::from("first_install")).exists();
+        fs::remove_file("first_install")?;
+
+        bombadil.install(false)?;
+        assert_that!(PathBuf::from("first_install")).does_not_exist();
+        assert_that!(PathBuf::from("profile_changed")).does_not_exist();
+
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.enable_profiles(vec!["work"])?;
+        bombadil.install(false)?;
+
+        // Assert
+        assert_that!(PathBuf::from("first_install")).does_not_exist();
+        assert_that!(PathBuf::from("profile_changed")).exists();
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_lifecycle_hooks"], before = setup("dotfiles_with_lifecycle_hooks"))]
+    fn unlink_hooks_run_around_symlinks_removal() -> Result<()> {
+        // Arrange
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.enable_profiles(vec!["work"])?;
+        bombadil.install(false)?;
+
+        // Act
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.uninstall()?;
+
+        // Assert
+        assert_that!(PathBuf::from("pre_unlink")).exists();
+        assert_that!(PathBuf::from("post_unlink")).exists();
+        assert_that!(PathBuf::from("work_unlinked")).exists();
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn secrets_use_file_and_secret_backends() -> Result<()> {
+        // Arrange
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Act
+        bombadil.install(false)?;
+        bombadil.add_secret("wifi", "hunter2", "dotfiles_with_secret_backends/vars.toml")?;
+        bombadil.reload_vars()?;
+
+        // Assert
+        assert_that!(fs::read_to_string(".netrc")?)
+            .is_equal_to("vpn vpn\ngithub ghp_token\nregistry registry-password\n".to_string());
+        let vars = fs::read_to_string("dotfiles_with_secret_backends/vars.toml")?;
+        assert_that!(vars).contains("secrets_backend");
+        assert_that!(vars).does_not_contain("hunter2");
+        assert_that!(bombadil.vars.get_secrets()?.get("wifi"))
+            .is_some()
+            .is_equal_to(&Value::String("hunter2".to_string()));
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn aborting_posthook_keeps_install_state() -> Result<()> {
+        // Arrange
+        fs::write(
+            "dotfiles_simple/bombadil.toml",
+            indoc! {r#"
+                dotfiles_dir = "dotfiles_simple"
+
+                [settings]
+                vars = [ "vars.toml" ]
+                posthooks = [ { command = "false", on_failure = "abort" } ]
+
+                [settings.dots]
+                css = { source = "template.css", target = ".config/template.css" }
+            "#},
+        )?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Act
+        let result = bombadil.install(false);
+
+        // Assert
+        assert_that!(result).is_err();
+        let state = BombadilState::read(bombadil.dotfiles_absolute_path()?)?;
+        assert_that!(state.symlinks).has_length(1);
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn rendered_secrets_are_private_and_ignored() -> Result<()> {
+        // Arrange
+        Repository::init("dotfiles_with_secret_backends")?;
+        let copy = env::current_dir()?.join("dotfiles_with_secret_backends/.dots/netrc");
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
 
-                writer.flush()?;
-            }
-        };
+        // Act
+        bombadil.install(false)?;
+        let state = BombadilState::read(bombadil.dotfiles_absolute_path()?)?;
+        let mode = fs::metadata(&copy)?.permissions().mode();
+        bombadil.uninstall()?;
 
+        // Assert
+        assert_that!(mode & 0o777).is_equal_to(0o600);
+        assert_that!(state.secret_renders).contains(&copy);
+        assert_that!(fs::read_to_string(
+            "dotfiles_with_secret_backends/.gitignore"
+        )?)
+        .is_equal_to(".dots/\n".to_string());
+        assert_that!(copy).does_not_exist();
         Ok(())
     }
 
-    fn rows_to_writer(writer: &mut (impl Write + Sized), rows: Vec<String>) -> io::Result<()> {
-        if !rows.is_empty() {
-            writer.write_all(rows.join("\n").as_bytes())?;
-            writer.flush()?;
-        }
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn rendered_secrets_are_ignored_when_a_posthook_aborts() -> Result<()> {
+        // Arrange
+        Repository::init("dotfiles_with_secret_backends")?;
+        let copy = env::current_dir()?.join("dotfiles_with_secret_backends/.dots/netrc");
+        fs::write(
+            "dotfiles_with_secret_backends/bombadil.toml",
+            indoc! {r#"
+                dotfiles_dir = "dotfiles_with_secret_backends"
+
+                [settings]
+                vars = [ "vars.toml" ]
+                posthooks = [ { command = "false", on_failure = "abort" } ]
+
+                [settings.dots]
+                netrc = { source = "netrc", target = ".netrc" }
+            "#},
+        )?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Act
+        let result = bombadil.install(false);
 
+        // Assert
+        assert_that!(result).is_err();
+        let state = BombadilState::read(bombadil.dotfiles_absolute_path()?)?;
+        assert_that!(state.secret_renders).contains(&copy);
+        assert_that!(fs::read_to_string(
+            "dotfiles_with_secret_backends/.gitignore"
+        )?)
+        .is_equal_to(".dots/\n".to_string());
         Ok(())
     }
 
-    fn dotfiles_absolute_path(&self) -> Result<PathBuf> {
-        dirs::home_dir()
-            .ok_or_else(|| anyhow!("$HOME dir not found"))
-            .map(|path| path.join(&self.path))
-    }
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn rendered_secrets_in_runtime_dir() -> Result<()> {
+        // Arrange
+        fs::create_dir("run")?;
+        env::set_var("XDG_RUNTIME_DIR", env::current_dir()?.join("run"));
+        let mut settings = OpenOptions::new()
+            .append(true)
+            .open("dotfiles_with_secret_backends/bombadil.toml")?;
+        writeln!(settings, "\n[settings.secrets]\nruntime_dir = true")?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
 
-    fn get_auto_ignored_files(&self, dot_key: &str) -> Vec<PathBuf> {
-        let dot_origin = self.dots.get(dot_key);
-        let origin_source = dot_origin.map(|dot| &dot.source);
+        // Act
+        bombadil.install(false)?;
 
-        let mut ignored: Vec<PathBuf> = self
-            .profiles
-            .iter()
-            .filter_map(|(_, profile)| profile.dots.get(dot_key))
-            .filter(|dot| dot.vars.is_some())
-            .filter_map(|dot| dot.resolve_var_path(origin_source))
-            .collect();
+        // Assert
+        let rendered = env::current_dir()?.join("run/bombadil/netrc");
+        assert_that!(fs::read_to_string(".netrc")?).contains("github ghp_token");
+        assert_that!(fs::read_link("dotfiles_with_secret_backends/.dots/netrc")?)
+            .is_equal_to(&rendered);
+        assert_that!(fs::metadata(&rendered)?.permissions().mode() & 0o777).is_equal_to(0o600);
+        Ok(())
+    }
 
-        let _ = dot_origin.map(|dot| dot.resolve_var_path().map(|path| ignored.push(path)));
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn install_encrypted_file_dot() -> Result<()> {
+        // Arrange
+        let identity = env::current_dir()?.join("dotfiles_with_secret_backends/age.key");
+        let mut settings = OpenOptions::new()
+            .append(true)
+            .open("dotfiles_with_secret_backends/bombadil.toml")?;
+        writeln!(
+            settings,
+            "kube = {{ source = \"kubeconfig.age\", target = \".kube/config\" }}\n\n\
+            [settings.secrets]\nage_identity = {:?}\nage_recipients = [ \"age1test\" ]",
+            identity
+        )?;
+        fs::write("kubeconfig", "token: hunter2\n")?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
 
-        ignored
+        // Act
+        let encrypted = bombadil.encrypt_file(Path::new("kubeconfig"), None, true)?;
+        bombadil.install(false)?;
+
+        // Assert
+        let copy = env::current_dir()?.join("dotfiles_with_secret_backends/.dots/kubeconfig.age");
+        assert_that!(encrypted.ends_with("dotfiles_with_secret_backends/kubeconfig.age")).is_true();
+        assert_that!(fs::read_to_string(&encrypted)?).does_not_contain("hunter2");
+        assert_that!(fs::read_to_string(".kube/config")?)
+            .is_equal_to("token: hunter2\n".to_string());
+        assert_that!(fs::metadata(&copy)?.permissions().mode() & 0o777).is_equal_to(0o600);
+        Ok(())
     }
-}
 
-pub enum MetadataType {
-    Dots,
-    PreHooks,
-    PostHooks,
-    Path,
-    Profiles,
-    Vars,
-    Secrets,
-}
+    #[sealed_test(files = ["tests/dotfiles_with_colliding_targets"], before = setup("dotfiles_with_colliding_targets"))]
+    fn install_with_colliding_targets_fails() -> Result<()> {
+        // Arrange
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.enable_profiles(vec!["work"])?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::paths::unlink;
-    use crate::Mode::NoGpg;
-    use cmd_lib::run_cmd;
-    use indoc::indoc;
-    use pretty_assertions::assert_eq;
-    use sealed_test::prelude::*;
-    use speculoos::prelude::*;
-    use std::ffi::OsStr;
-    use std::{env, fs};
+        // Act
+        let result = bombadil.install(false);
 
-    fn setup(dotfiles: &str) {
-        let home_dir = env::current_dir().unwrap().canonicalize().unwrap();
-        env::set_var("HOME", home_dir);
+        // Assert
+        let error = result.unwrap_err().to_string();
+        assert_that!(error).contains("dots `sway` (bombadil.toml) and `sway_config` (import ");
+        assert_that!(error).contains("desktop.toml\") are both linked to");
+        assert_that!(error).contains("dot `kitty` (profile `work`) is linked to");
+        assert_that!(error).contains("inside the target of dot `sway` (bombadil.toml)");
+        assert_that!(Path::new(".config/sway").exists()).is_false();
+        Ok(())
+    }
 
-        #[cfg(target_os = "macos")]
-        run_cmd!(mkdir -p "Library/Application Support";).unwrap();
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn decrypt_restored_encrypted_file_with_older_mtime() -> Result<()> {
+        // Arrange
+        let identity = env::current_dir()?.join("dotfiles_with_secret_backends/age.key");
+        let mut settings = OpenOptions::new()
+            .append(true)
+            .open("dotfiles_with_secret_backends/bombadil.toml")?;
+        writeln!(
+            settings,
+            "kube = {{ source = \"kubeconfig.age\", target = \".kube/config\" }}\n\n\
+            [settings.secrets]\nage_identity = {:?}\nage_recipients = [ \"age1test\" ]",
+            identity
+        )?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        fs::write("kubeconfig", "token: old\n")?;
+        let encrypted = bombadil.encrypt_file(Path::new("kubeconfig"), None, true)?;
+        bombadil.install(false)?;
 
-        run_cmd!(
-            mkdir .config;
-        )
-        .unwrap();
+        // Act
+        fs::write("kubeconfig", "token: restored\n")?;
+        bombadil.encrypt_file(Path::new("kubeconfig"), None, true)?;
+        run_cmd!(touch -d "2000-01-01" $encrypted)?;
+        bombadil.install(false)?;
 
-        Bombadil::link_self_config(Some(PathBuf::from(dotfiles))).unwrap();
+        // Assert
+        assert_that!(fs::read_to_string(".kube/config")?)
+            .is_equal_to("token: restored\n".to_string());
+        Ok(())
     }
 
-    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
-    fn self_link_works() {
-        let link = dirs::config_dir().unwrap().join(BOMBADIL_CONFIG);
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn edit_and_list_secrets() -> Result<()> {
+        // Arrange
+        env::set_var("EDITOR", "sed -i s/vpn/tunnel/");
+        env::remove_var("VISUAL");
+        let bombadil = Bombadil::from_settings(NoGpg)?;
+        let var_file = Path::new("dotfiles_with_secret_backends/vars.toml");
 
-        assert_that!(link).exists();
+        // Act
+        bombadil.edit_secret("vpn", var_file)?;
+        let mut out = vec![];
+        bombadil.list_secrets(None, &mut out)?;
+
+        // Assert
+        assert_that!(VarFile::open(var_file)?.get("vpn")?).is_equal_to("tunnel".to_string());
+        let out = String::from_utf8(out)?;
+        assert_that!(out).contains("vars.toml]");
+        assert_that!(out).contains("\tage\n");
+        assert_that!(out).contains("\tpass\n");
+        assert_that!(out).contains("\tcommand\n");
+        Ok(())
     }
 
-    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
-    fn install_single_file_works() -> Result<()> {
-        Bombadil::from_settings(NoGpg)?.install(false)?;
+    #[sealed_test(files = ["tests/dotfiles_with_systemd"], before = setup("dotfiles_with_systemd"))]
+    fn systemd_units_reload_only_on_change() -> Result<()> {
+        // Arrange
+        let stub_path = env::current_dir()?.join("dotfiles_with_systemd/bin");
+        env::set_var(
+            "PATH",
+            format!("{}:{}", stub_path.display(), env::var("PATH")?),
+        );
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
 
-        let target = fs::read_link(".config/template.css")?;
-        let expected = env::current_dir()?.join("dotfiles_simple/.dots/template.css");
+        // Act
+        bombadil.install(false)?;
+        let first_install = fs::read_to_string("systemctl.log")?;
+        fs::remove_file("systemctl.log")?;
 
-        assert_that!(target).is_equal_to(expected);
+        bombadil.install(false)?;
+        assert_that!(PathBuf::from("systemctl.log")).does_not_exist();
 
-        let target = std::fs::read_to_string(target)?;
+        fs::write(
+            "dotfiles_with_systemd/vars.toml",
+            "mako_args = \"--max-visible 5\"",
+        )?;
+        bombadil.reload_vars()?;
+        bombadil.install(false)?;
+        let update = fs::read_to_string("systemctl.log")?;
+        fs::remove_file("systemctl.log")?;
 
-        assert_eq!(
-            target,
-            indoc! {
-                ".class {
-                    color: #de1f1f
-                }
-                "
-            }
+        bombadil.uninstall()?;
+        let uninstall = fs::read_to_string("systemctl.log")?;
+
+        // Assert
+        assert_that!(first_install).is_equal_to(
+            "--user daemon-reload\n--user enable mako.service\n--user restart mako.service\n"
+                .to_string(),
+        );
+        assert_that!(update).is_equal_to(
+            "--user daemon-reload\n--user enable mako.service\n--user restart mako.service\n"
+                .to_string(),
+        );
+        assert_that!(uninstall)
+            .is_equal_to("--user disable --now mako.service\n--user daemon-reload\n".to_string());
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_systemd"], before = setup("dotfiles_with_systemd"))]
+    fn systemd_units_removed_from_settings_are_disabled() -> Result<()> {
+        // Arrange
+        let stub_path = env::current_dir()?.join("dotfiles_with_systemd/bin");
+        env::set_var(
+            "PATH",
+            format!("{}:{}", stub_path.display(), env::var("PATH")?),
         );
+        Bombadil::from_settings(NoGpg)?.install(false)?;
+        fs::remove_file("systemctl.log")?;
+        fs::write(
+            "dotfiles_with_systemd/bombadil.toml",
+            "dotfiles_dir = \"dotfiles_with_systemd\"",
+        )?;
+
+        // Act
+        Bombadil::from_settings(NoGpg)?.install(false)?;
+        let install = fs::read_to_string("systemctl.log")?;
+        fs::remove_file("systemctl.log")?;
 
+        Bombadil::from_settings(NoGpg)?.uninstall()?;
+
+        // Assert
+        assert_that!(install)
+            .is_equal_to("--user disable --now mako.service\n--user daemon-reload\n".to_string());
+        assert_that!(PathBuf::from(".config/systemd/user")).does_not_exist();
+        assert_that!(PathBuf::from("systemctl.log")).does_not_exist();
         Ok(())
     }
 
-    #[sealed_test(files = ["tests/dotfiles_direct"], before = setup("dotfiles_direct"))]
-    fn install_direct_file_works() -> Result<()> {
+    #[sealed_test(files = ["tests/dotfiles_with_systemd"], before = setup("dotfiles_with_systemd"))]
+    fn uninstall_disables_installed_units_removed_from_settings() -> Result<()> {
+        // Arrange
+        let stub_path = env::current_dir()?.join("dotfiles_with_systemd/bin");
+        env::set_var(
+            "PATH",
+            format!("{}:{}", stub_path.display(), env::var("PATH")?),
+        );
         Bombadil::from_settings(NoGpg)?.install(false)?;
+        fs::remove_file("systemctl.log")?;
+        fs::write(
+            "dotfiles_with_systemd/bombadil.toml",
+            "dotfiles_dir = \"dotfiles_with_systemd\"",
+        )?;
 
-        let target = fs::read_link(".config/file.txt")?;
-        let expected = env::current_dir()?.join("dotfiles_direct/file.txt");
+        // Act
+        Bombadil::from_settings(NoGpg)?.uninstall()?;
 
-        assert_that!(target).is_equal_to(expected);
+        // Assert
+        let uninstall = fs::read_to_string("systemctl.log")?;
+        assert_that!(uninstall)
+            .is_equal_to("--user disable --now mako.service\n--user daemon-reload\n".to_string());
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_packages"], before = setup("dotfiles_with_packages"))]
+    fn profile_packages_are_installed() -> Result<()> {
+        // Arrange
+        fs::write("zsh", "")?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.enable_profiles(vec!["sway"])?;
+
+        // Act
+        let installed = bombadil.install_packages()?;
 
+        // Assert
+        assert_that!(installed).is_equal_to(vec![
+            "alacritty".to_string(),
+            "sway".to_string(),
+            "mako".to_string(),
+        ]);
+        assert_that!(bombadil.missing_packages()?).is_empty();
         Ok(())
     }
-    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
-    fn force_install_single_file_works() -> Result<()> {
-        fs::write(".config/template.css", "foo")?;
-        Bombadil::from_settings(NoGpg)?.install(true)?;
 
-        let target = fs::read_link(".config/template.css")?;
-        let expected = env::current_dir()?.join("dotfiles_simple/.dots/template.css");
+    #[test]
+    fn should_parse_profile_selection() -> Result<()> {
+        let available = vec!["sway".to_string(), "work".to_string()];
 
-        assert_that!(target).is_equal_to(expected);
+        assert_that!(parse_profile_selection("2, sway\n", &available)?)
+            .is_equal_to(vec!["work".to_string(), "sway".to_string()]);
+        assert_that!(parse_profile_selection("\n", &available)?).is_empty();
+        assert_that!(parse_profile_selection("3", &available)).is_err();
+        assert_that!(parse_profile_selection("i3", &available)).is_err();
+        Ok(())
+    }
 
-        let target = std::fs::read_to_string(target)?;
+    #[sealed_test]
+    fn clone_enables_host_profiles_and_resumes() -> Result<()> {
+        // Arrange
+        let home = setup_home();
+        fs::create_dir_all("upstream/dotfiles.git")?;
+        fs::write("upstream/dotfiles.git/zshrc", "export EDITOR=vim")?;
+        fs::write(
+            "upstream/dotfiles.git/bombadil.toml",
+            format!(
+                indoc! {r#"
+                    dotfiles_dir = "dotfiles"
+
+                    [settings.dots]
+                    zsh = {{ source = "zshrc", target = ".zshrc" }}
+
+                    [profiles.laptop]
+                    posthooks = [ "touch $HOME/laptop_enabled" ]
+
+                    [hosts]
+                    "{}" = [ "laptop" ]
+                "#},
+                hostname().expect("hostname")
+            ),
+        )?;
+        commit_all(Path::new("upstream/dotfiles.git"))?;
+        let remote = home.join("upstream/dotfiles.git");
+        let remote = remote.to_string_lossy();
 
-        assert_eq!(
-            target,
-            indoc! {
-                ".class {
-                    color: #de1f1f
-                }
-                "
-            }
-        );
+        // Act
+        Bombadil::install_from_remote(&remote, None, &CloneOptions::default(), None, false)?;
+        fs::remove_file("laptop_enabled")?;
+        Bombadil::install_from_remote(&remote, None, &CloneOptions::default(), None, false)?;
 
+        // Assert
+        assert_that!(PathBuf::from(".zshrc")).exists();
+        assert_that!(PathBuf::from("laptop_enabled")).exists();
         Ok(())
     }
 
-    #[sealed_test(files = ["tests/dotfiles_invalid_dot"], before = setup("dotfiles_invalid_dot"))]
-    fn install_should_fail_and_continue() -> Result<()> {
+    #[sealed_test]
+    fn pull_relinks_with_previous_profiles() -> Result<()> {
+        // Arrange
+        let home = setup_home();
+        fs::create_dir_all("upstream/dotfiles")?;
+        fs::write("upstream/dotfiles/zshrc", "export EDITOR=vim")?;
+        fs::write(
+            "upstream/dotfiles/bombadil.toml",
+            indoc! {r#"
+                dotfiles_dir = "dotfiles"
+
+                [settings.dots]
+                zsh = { source = "zshrc", target = ".zshrc" }
+
+                [profiles.laptop]
+                posthooks = [ "touch $HOME/laptop_enabled" ]
+            "#},
+        )?;
+        commit_all(Path::new("upstream/dotfiles"))?;
+        let remote = home.join("upstream/dotfiles");
+        let options = CloneOptions::default();
+        Bombadil::install_from_remote(
+            &remote.to_string_lossy(),
+            None,
+            &options,
+            Some(vec!["laptop"]),
+            false,
+        )?;
+        fs::remove_file("laptop_enabled")?;
+
+        // Commit a change upstream
+        commit_file(&remote, "zshrc", "export EDITOR=nvim")?;
+
         // Act
-        Bombadil::from_settings(NoGpg)?.install(false)?;
+        Bombadil::pull(false)?;
 
         // Assert
-        assert_that!(PathBuf::from(".config/template.css")).exists();
-        assert_that!(PathBuf::from(".config/invalid")).does_not_exist();
+        assert_that!(fs::read_to_string(".zshrc")?).is_equal_to("export EDITOR=nvim".to_string());
+        assert_that!(PathBuf::from("laptop_enabled")).exists();
         Ok(())
     }
 
-    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
-    fn uninstall_works() -> Result<()> {
-        Bombadil::link_self_config(Some(PathBuf::from("dotfiles_simple")))?;
+    #[sealed_test]
+    fn install_external_dot_at_pinned_rev() -> Result<()> {
+        // Arrange
+        let home = setup_home();
+        fs::create_dir_all("themes/alacritty")?;
+        fs::write("themes/alacritty/colors.toml", "background = \"#282828\"")?;
+        let pinned = commit_all(Path::new("themes"))?;
+        fs::create_dir("dotfiles")?;
+        fs::write(
+            "dotfiles/bombadil.toml",
+            format!(
+                indoc! {r#"
+                    dotfiles_dir = "dotfiles"
+
+                    [settings.dots]
+                    colors = {{ git = "{}", rev = "{}", source = "alacritty", target = ".config/alacritty" }}
+                "#},
+                home.join("themes").display(),
+                pinned
+            ),
+        )?;
+        Bombadil::link_self_config(Some(PathBuf::from("dotfiles")))?;
+
+        // Commit a change upstream, the pinned revision is kept
+        commit_file(Path::new("themes"), "README.md", "# Themes")?;
+
+        // Act
         let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.install(false)?;
+
+        // Assert
+        assert_that!(fs::read_to_string(".config/alacritty/colors.toml")?)
+            .is_equal_to("background = \"#282828\"".to_string());
+        let external = bombadil.dots["colors"].external_repository().unwrap();
+        assert_that!(Repository::open(&external)?.head()?.target()).is_equal_to(Some(pinned));
+        assert_that!(external.join("README.md")).does_not_exist();
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn install_external_dot_with_local_vars() -> Result<()> {
+        // Arrange
+        let home = setup_home();
+        fs::create_dir_all("themes/alacritty")?;
+        fs::write(
+            "themes/alacritty/colors.toml",
+            "background = \"{{ background }}\"",
+        )?;
+        fs::write("themes/alacritty/vars.toml", "background = \"#282828\"")?;
+        commit_all(Path::new("themes"))?;
+        fs::create_dir("dotfiles")?;
+        fs::write(
+            "dotfiles/bombadil.toml",
+            format!(
+                indoc! {r#"
+                    dotfiles_dir = "dotfiles"
+
+                    [settings.dots]
+                    colors = {{ git = "{}", source = "alacritty", target = ".config/alacritty" }}
+                "#},
+                home.join("themes").display(),
+            ),
+        )?;
+        Bombadil::link_self_config(Some(PathBuf::from("dotfiles")))?;
 
+        // Act
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
         bombadil.install(false)?;
-        assert_that!(PathBuf::from(".config/template.css")).exists();
 
-        bombadil.uninstall()?;
-        assert_that!(PathBuf::from(".config/template.css")).does_not_exist();
+        // Assert
+        assert_that!(fs::read_to_string(".config/alacritty/colors.toml")?)
+            .is_equal_to("background = \"#282828\"".to_string());
+        assert_that!(PathBuf::from(".config/alacritty/vars.toml")).does_not_exist();
         Ok(())
     }
 
     #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
-    fn posthook_ok() -> Result<()> {
+    fn git_commit_lists_touched_dots() -> Result<()> {
+        // Arrange
+        commit_all(Path::new("dotfiles_simple"))?;
+        let repository = Repository::open("dotfiles_simple")?;
+        repository.config()?.set_str("user.name", "bombadil")?;
+        repository
+            .config()?
+            .set_str("user.email", "bombadil@example.org")?;
+        fs::write("dotfiles_simple/template.css", ".class { color: red }")?;
+        fs::write("dotfiles_simple/notes.md", "# Notes")?;
         let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.install(false)?;
 
         // Act
-        bombadil.install(false)?;
+        let mut status = vec![];
+        bombadil.git_status(&mut status)?;
+        bombadil.git_commit(None)?;
+        let mut status_after_commit = vec![];
+        bombadil.git_status(&mut status_after_commit)?;
 
         // Assert
-        assert_that!(PathBuf::from(".config/posthook/file").exists());
+        let status = String::from_utf8(status)?;
+        assert_that!(status).contains("css");
+        assert_that!(status).contains("\ttemplate.css");
+        assert_that!(status).contains("notes.md");
+        assert_that!(status).does_not_contain(".dots");
+        let head = repository.head()?.peel_to_commit()?;
+        assert_that!(head.message()).is_equal_to(Some("Update dots: css"));
+        assert_that!(String::from_utf8(status_after_commit)?)
+            .is_equal_to("Nothing to commit, dotfiles are clean\n".to_string());
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn clone_refuses_non_repository_target() -> Result<()> {
+        // Arrange
+        fs::create_dir("dotfiles")?;
+        fs::write("dotfiles/notes", "")?;
+
+        // Act
+        let result = Bombadil::install_from_remote(
+            "https://example.org/dotfiles.git",
+            None,
+            &CloneOptions::default(),
+            None,
+            false,
+        );
 
+        // Assert
+        assert_that!(result).is_err();
         Ok(())
     }
 
-    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
-    fn prehook_ok() -> Result<()> {
+    #[sealed_test(files = ["tests/dotfiles_with_dot_hooks"], before = setup("dotfiles_with_dot_hooks"))]
+    fn dot_posthook_from_profile() -> Result<()> {
         let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.enable_profiles(vec!["work"])?;
 
         // Act
         bombadil.install(false)?;
 
         // Assert
-        assert_that!(PathBuf::from(".config/prehook_file")).exists();
-
+        assert_that!(PathBuf::from("zsh_reloaded")).exists();
         Ok(())
     }
 
@@ -874,6 +2391,22 @@ mod tests {
         Ok(())
     }
 
+    #[sealed_test(files = ["tests/dotfiles_with_imported_hosts"], before = setup("dotfiles_with_imported_hosts"))]
+    fn should_merge_imported_hosts() -> Result<()> {
+        // Act
+        let bombadil = Bombadil::from_settings(NoGpg)?;
+
+        // Assert
+        assert_that!(bombadil.hosts.get("desktop"))
+            .is_some()
+            .is_equal_to(&vec!["sway".to_string()]);
+        assert_that!(bombadil.hosts.get("laptop"))
+            .is_some()
+            .is_equal_to(&vec!["work".to_string()]);
+        assert_that!(bombadil.profiles.get("work")).is_some();
+        Ok(())
+    }
+
     #[sealed_test(files = ["tests/dotfiles_with_profile_context"], before = setup("dotfiles_with_profile_context"))]
     fn should_have_profile_context() -> Result<()> {
         // Arrange
@@ -1027,5 +2560,53 @@ mod tests {
 
             Ok(())
         }
+
+        #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+        fn should_mask_secrets_unless_revealed() -> Result<()> {
+            // Arrange
+            let bombadil = Bombadil::from_settings(NoGpg)?;
+
+            // Act
+            let masked = bombadil.print_metadata_to_string(MetadataType::Secrets)?;
+            let mut revealed = vec![];
+            bombadil.print_secrets(true, &mut revealed, true)?;
+            let revealed = String::from_utf8(revealed)?;
+
+            // Assert
+            assert_that!(masked).contains("vpn");
+            assert_that!(masked).contains(SECRET_MASK);
+            assert_that!(masked).does_not_contain("ghp_token");
+            assert_that!(revealed).contains("ghp_token");
+            assert_that!(revealed).does_not_contain(SECRET_MASK);
+
+            Ok(())
+        }
+
+        #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+        fn should_list_masked_secrets_without_decrypting() -> Result<()> {
+            // Arrange
+            let bombadil = Bombadil::from_settings(NoGpg)?;
+            for backend in ["age", "pass"] {
+                fs::write(
+                    format!("dotfiles_with_secret_backends/bin/{backend}"),
+                    "#!/bin/sh\ntouch \"$HOME/decrypted\"\nexit 1\n",
+                )?;
+            }
+
+            // Act
+            let masked = bombadil.print_metadata_to_string(MetadataType::Secrets)?;
+
+            // Assert
+            let json: Value = serde_json::from_str(&masked)?;
+            assert_that!(json).is_equal_to(json!({
+                "secrets": {
+                    "github": SECRET_MASK,
+                    "registry": SECRET_MASK,
+                    "vpn": SECRET_MASK,
+                }
+            }));
+            assert_that!(PathBuf::from("decrypted")).does_not_exist();
+            Ok(())
+        }
     }
 }
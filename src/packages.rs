@@ -0,0 +1,173 @@
+use crate::settings::packages::PackageSettings;
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use std::process::{Command, Stdio};
+use tera::{Context, Tera};
+
+const DEFAULT_PROBE: &str = "which {{ package }}";
+
+// Render a command template with `variable` standing for `"$1"` or `"$@"`, package names are then
+// passed to `sh -c` as positional arguments so the shell never interprets them
+fn render_command(template: &str, variable: &str, value: &str) -> tera::Result<String> {
+    let mut context = Context::new();
+    context.insert(variable, value);
+    Tera::one_off(template, &context, false)
+}
+
+impl PackageSettings {
+    /// Declared packages the probe command could not find
+    pub(crate) fn missing(&self) -> Result<Vec<String>> {
+        let probe = self.probe.as_deref().unwrap_or(DEFAULT_PROBE);
+        let mut missing = vec![];
+
+        let command = render_command(probe, "package", "\"$1\"")
+            .map_err(|err| anyhow!("Invalid package probe `{}` : {}", probe, err))?;
+
+        for package in &self.list {
+            let installed = Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .arg("sh")
+                .arg(package)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map_err(|err| anyhow!("Failed to run `{}` for {} : {}", probe, package, err))?
+                .success();
+
+            if !installed {
+                missing.push(package.clone());
+            }
+        }
+
+        Ok(missing)
+    }
+
+    /// Run the installer command for the given packages
+    pub(crate) fn install(&self, packages: &[String]) -> Result<()> {
+        if packages.is_empty() {
+            return Ok(());
+        }
+
+        let Some(installer) = &self.installer else {
+            return Err(anyhow!("No `installer` configured in [settings.packages]"));
+        };
+
+        let invalid = |err| anyhow!("Invalid package installer `{}` : {}", installer, err);
+        let command = render_command(installer, "packages", "\"$@\"").map_err(invalid)?;
+        let display =
+            render_command(installer, "packages", &packages.join(" ")).map_err(invalid)?;
+
+        println!("Installing packages : {}", display.green());
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .arg("sh")
+            .args(packages)
+            .status()
+            .map_err(|err| anyhow!("Failed to run `{}` : {}", display, err))?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!("Package installation failed: {}", status))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::packages::PackageSettings;
+    use anyhow::Result;
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::path::PathBuf;
+    use std::{env, fs};
+
+    #[test]
+    fn should_find_missing_packages_with_default_probe() -> Result<()> {
+        // Arrange
+        let packages = PackageSettings {
+            list: vec![
+                "sh".to_string(),
+                "surely-not-an-installed-package".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        // Act
+        let missing = packages.missing()?;
+
+        // Assert
+        assert_that!(missing).is_equal_to(vec!["surely-not-an-installed-package".to_string()]);
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_install_missing_packages() -> Result<()> {
+        // Arrange
+        env::set_var("HOME", env::current_dir()?);
+        fs::write("alacritty", "")?;
+        let packages = PackageSettings {
+            installer: Some("cd $HOME && touch {{ packages }}".to_string()),
+            probe: Some("test -e $HOME/{{ package }}".to_string()),
+            list: vec![
+                "alacritty".to_string(),
+                "sway".to_string(),
+                "mako".to_string(),
+            ],
+        };
+
+        // Act
+        let missing = packages.missing()?;
+        packages.install(&missing)?;
+
+        // Assert
+        assert_that!(missing).is_equal_to(vec!["sway".to_string(), "mako".to_string()]);
+        assert_that!(PathBuf::from("sway")).exists();
+        assert_that!(PathBuf::from("mako")).exists();
+        assert_that!(packages.missing()?).is_empty();
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_pass_package_names_verbatim() -> Result<()> {
+        // Arrange
+        env::set_var("HOME", env::current_dir()?);
+        let packages = PackageSettings {
+            installer: Some("cd $HOME && touch {{ packages }}".to_string()),
+            probe: Some("test -e $HOME/{{ package }}".to_string()),
+            list: vec![
+                "with space".to_string(),
+                "semi;touch injected".to_string(),
+                "$(touch substituted)".to_string(),
+            ],
+        };
+
+        // Act
+        let missing = packages.missing()?;
+        packages.install(&missing)?;
+
+        // Assert
+        assert_that!(missing).is_equal_to(packages.list.clone());
+        assert_that!(PathBuf::from("with space")).exists();
+        assert_that!(PathBuf::from("semi;touch injected")).exists();
+        assert_that!(PathBuf::from("$(touch substituted)")).exists();
+        assert_that!(PathBuf::from("injected")).does_not_exist();
+        assert_that!(PathBuf::from("substituted")).does_not_exist();
+        assert_that!(packages.missing()?).is_empty();
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_to_install_without_installer() {
+        // Arrange
+        let packages = PackageSettings::default();
+
+        // Act
+        let result = packages.install(&["sway".to_string()]);
+
+        // Assert
+        assert_that!(result).is_err();
+    }
+}
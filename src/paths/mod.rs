@@ -1,8 +1,12 @@
 use crate::error::Error::{SourceNotFound, Symlink, TargetNotFound, TemplateNotFound, Unlink};
 use crate::error::*;
+use crate::git::repository_name;
 use crate::settings::dotfile_dir;
 use crate::{Dot, DotVar};
 use dirs::home_dir;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::os::unix;
 use std::path::{Path, PathBuf};
@@ -31,6 +35,15 @@ pub trait DotPaths {
     fn symlink_direct(&self, force: bool) -> Result<()>;
 
     fn resolve_var_path(&self) -> Option<PathBuf>;
+
+    /// The dot source relative to the dotfiles directory, or to `.dots/external` for external dots
+    fn relative_source(&self) -> PathBuf;
+
+    /// Where the external repository of this dot is cloned
+    fn external_repository(&self) -> Option<PathBuf>;
+
+    /// The directory `source` is relative to, the dotfiles directory or the external repository
+    fn source_root(&self) -> PathBuf;
 }
 
 impl DotPaths for Dot {
@@ -49,7 +62,13 @@ impl DotPaths for Dot {
     }
 
     fn source(&self) -> Result<PathBuf> {
-        let path = dotfile_dir().join(&self.source);
+        let path = match &self.git {
+            Some(_) => external_dir().join(self.relative_source()),
+            None if self.source.as_os_str().is_empty() => {
+                return Err(SourceNotFound(self.source.clone()))
+            }
+            None => dotfile_dir().join(&self.source),
+        };
 
         if path.exists() {
             Ok(path)
@@ -70,7 +89,7 @@ impl DotPaths for Dot {
     }
 
     fn copy_path_unchecked(&self) -> PathBuf {
-        dotfile_dir().join(".dots").join(&self.source)
+        dotfile_dir().join(".dots").join(self.relative_source())
     }
 
     fn unlink(&self) -> Result<()> {
@@ -167,10 +186,112 @@ impl DotPaths for Dot {
     }
 
     fn resolve_var_path(&self) -> Option<PathBuf> {
-        self.resolve_from_source(&self.source, &self.vars)
+        self.resolve_from_source(&self.source_root(), &self.source, &self.vars)
+    }
+
+    fn relative_source(&self) -> PathBuf {
+        match &self.git {
+            Some(url) => Path::new(&external_dir_name(url)).join(&self.source),
+            None => self.source.clone(),
+        }
+    }
+
+    fn external_repository(&self) -> Option<PathBuf> {
+        self.git.as_deref().map(external_repository)
+    }
+
+    fn source_root(&self) -> PathBuf {
+        self.external_repository().unwrap_or_else(dotfile_dir)
+    }
+}
+
+/// Two dots linked to the same target, or one linked inside the target of the other
+#[derive(Debug)]
+pub(crate) struct TargetCollision<'a> {
+    /// The dot containing the other one, the first key when both have the same target
+    pub(crate) outer: (&'a String, &'a Dot),
+    pub(crate) inner: (&'a String, &'a Dot),
+    /// Target of the inner dot
+    pub(crate) target: PathBuf,
+    nested: bool,
+}
+
+impl fmt::Display for TargetCollision<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (outer, outer_dot) = self.outer;
+        let (inner, inner_dot) = self.inner;
+        if self.nested {
+            write!(
+                f,
+                "dot `{inner}` ({}) is linked to {:?}, inside the target of dot `{outer}` ({})",
+                inner_dot.origin, self.target, outer_dot.origin
+            )
+        } else {
+            write!(
+                f,
+                "dots `{outer}` ({}) and `{inner}` ({}) are both linked to {:?}",
+                outer_dot.origin, inner_dot.origin, self.target
+            )
+        }
     }
 }
 
+/// Find the dots linked to the same target or inside the target of another dot,
+/// the latest symlink would otherwise silently win or fail
+pub(crate) fn target_collisions(dots: &HashMap<String, Dot>) -> Vec<TargetCollision<'_>> {
+    let mut targets: Vec<(PathBuf, &String, &Dot)> = dots
+        .iter()
+        .filter_map(|(key, dot)| dot.target().ok().map(|target| (target, key, dot)))
+        .collect();
+
+    // Paths are ordered by components, a target comes before the ones it contains
+    targets.sort_by(|(a, a_key, _), (b, b_key, _)| (a, a_key).cmp(&(b, b_key)));
+
+    let mut collisions = vec![];
+    for (index, (target, key, dot)) in targets.iter().enumerate() {
+        for (other_target, other_key, other_dot) in &targets[index + 1..] {
+            if other_target.starts_with(target) {
+                collisions.push(TargetCollision {
+                    outer: (key, dot),
+                    inner: (other_key, other_dot),
+                    target: other_target.clone(),
+                    nested: other_target != target,
+                });
+            }
+        }
+    }
+
+    collisions
+}
+
+/// Cache directory of external dot repositories
+pub(crate) fn external_dir() -> PathBuf {
+    dotfile_dir().join(".dots").join("external")
+}
+
+/// Where the external repository cloned from `url` lives
+pub(crate) fn external_repository(url: &str) -> PathBuf {
+    external_dir().join(external_dir_name(url))
+}
+
+// A stable directory name for an external repository url, the repository name keeps it readable
+// and a short hash of the url keeps repositories with similar urls apart
+fn external_dir_name(url: &str) -> String {
+    let name: String = repository_name(url)
+        .unwrap_or("repository")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+
+    let hash: String = Sha256::digest(url.as_bytes())
+        .iter()
+        .take(6)
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    format!("{name}-{hash}")
+}
+
 pub fn unlink<P: AsRef<Path> + ?Sized>(path: &P) -> Result<()> {
     if fs::symlink_metadata(path).is_ok() {
         if path.as_ref().is_dir() {
@@ -188,3 +309,20 @@ pub fn unlink<P: AsRef<Path> + ?Sized>(path: &P) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::paths::external_dir_name;
+    use speculoos::prelude::*;
+
+    #[test]
+    fn should_name_external_repositories_apart() {
+        let dotted = external_dir_name("https://example.org/themes/a.b");
+        let dashed = external_dir_name("https://example.org/themes/a-b");
+
+        assert_that!(dotted).starts_with("a-b-");
+        assert_that!(dashed).starts_with("a-b-");
+        assert_that!(dotted).is_not_equal_to(&dashed);
+        assert_that!(external_dir_name("https://example.org/themes/a.b")).is_equal_to(&dotted);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::secrets::{pipe, pipe_file, SecretBackend};
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Secrets encrypted with the `age` binary, stored as armored messages
+pub(crate) struct Age {
+    identity: Option<PathBuf>,
+    recipients: Vec<String>,
+}
+
+impl Age {
+    pub(crate) fn new(identity: Option<PathBuf>, recipients: Vec<String>) -> Self {
+        Age {
+            identity,
+            recipients,
+        }
+    }
+
+    /// Encrypt a whole file to an armored message
+    pub(crate) fn encrypt_file(&self, path: &Path) -> Result<Vec<u8>> {
+        pipe_file(&mut self.encrypt_command()?, path)
+    }
+
+    /// Decrypt a whole file, either armored or binary
+    pub(crate) fn decrypt_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let identity = self
+            .identity()
+            .ok_or_else(|| anyhow!("No `age_identity` configured to decrypt {:?}", path))?;
+        pipe_file(
+            Command::new("age").args(["--decrypt", "-i", &identity]),
+            path,
+        )
+    }
+
+    fn encrypt_command(&self) -> Result<Command> {
+        if self.recipients.is_empty() {
+            return Err(anyhow!("No `recipients` configured for the age backend"));
+        }
+
+        let mut command = Command::new("age");
+        command.args(["--encrypt", "--armor"]);
+        for recipient in &self.recipients {
+            command.args(["-r", recipient]);
+        }
+
+        Ok(command)
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.identity
+            .as_ref()
+            .map(|identity| shellexpand::tilde(&identity.to_string_lossy()).to_string())
+    }
+}
+
+impl SecretBackend for Age {
+    fn encrypt(&self, _key: &str, value: &str) -> Result<String> {
+        pipe(&mut self.encrypt_command()?, value)
+    }
+
+    fn decrypt(&self, key: &str, stored: &str) -> Result<String> {
+        let Some(identity) = self.identity() else {
+            return Err(anyhow!(
+                "No `identity` configured to decrypt age secret `{}`",
+                key
+            ));
+        };
+
+        pipe(
+            Command::new("age").args(["--decrypt", "-i", &identity]),
+            stored,
+        )
+        .map_err(|err| anyhow!("Cannot decrypt secret `{}` : {}", key, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::age::Age;
+    use crate::secrets::SecretBackend;
+    use anyhow::Result;
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    #[sealed_test]
+    fn should_encrypt_and_decrypt_with_generated_key() -> Result<()> {
+        // Arrange
+        let Ok(keygen) = Command::new("age-keygen").args(["-o", "key.txt"]).status() else {
+            eprintln!("age-keygen not found, skipping");
+            return Ok(());
+        };
+        assert_that!(keygen.success()).is_true();
+        let recipient = Command::new("age-keygen")
+            .args(["-y", "key.txt"])
+            .output()?;
+        let recipient = String::from_utf8(recipient.stdout)?.trim().to_string();
+        let age = Age::new(Some(PathBuf::from("key.txt")), vec![recipient]);
+        fs::write("config", "token = hunter2")?;
+
+        // Act
+        let encrypted = age.encrypt("token", "hunter2")?;
+        let decrypted = age.decrypt("token", &encrypted)?;
+        fs::write("config.age", age.encrypt_file(Path::new("config"))?)?;
+        let decrypted_file = age.decrypt_file(Path::new("config.age"))?;
+
+        // Assert
+        assert_that!(encrypted).starts_with("-----BEGIN AGE ENCRYPTED FILE-----");
+        assert_that!(decrypted).is_equal_to("hunter2".to_string());
+        assert_that!(decrypted_file).is_equal_to(b"token = hunter2".to_vec());
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use crate::secrets::SecretBackend;
+use anyhow::{anyhow, Result};
+use std::process::Command;
+use tera::{Context, Tera};
+
+/// Secrets read from the output of a user command, e.g. `bw get password {{ key }}`
+pub(crate) struct CommandBackend {
+    command: String,
+}
+
+impl CommandBackend {
+    pub(crate) fn new(command: &str) -> Self {
+        CommandBackend {
+            command: command.to_string(),
+        }
+    }
+}
+
+// Quote a value as a single shell word
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+impl SecretBackend for CommandBackend {
+    fn encrypt(&self, key: &str, _value: &str) -> Result<String> {
+        Err(anyhow!(
+            "Cannot store secret `{}`, the command backend is read only",
+            key
+        ))
+    }
+
+    fn decrypt(&self, key: &str, stored: &str) -> Result<String> {
+        // Values are quoted so the shell never interprets them
+        let mut context = Context::new();
+        context.insert("key", &shell_quote(key));
+        context.insert("value", &shell_quote(stored));
+        let command = Tera::one_off(&self.command, &context, false)
+            .map_err(|err| anyhow!("Invalid secret command `{}` : {}", self.command, err))?;
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .output()
+            .map_err(|err| anyhow!("Failed to run `{}` : {}", command, err))?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Secret command `{}` failed: {}",
+                command,
+                output.status
+            ));
+        }
+
+        let secret = String::from_utf8(output.stdout)?;
+        Ok(secret.trim_end_matches('\n').to_string())
+    }
+
+    fn supports_blob(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+/// Where the value of a new secret is read from
+#[derive(Debug, Clone)]
+pub enum SecretInput {
+    /// Standard input, the value is not echoed when reading from a terminal
+    Stdin,
+    /// A value given on the command line
+    Value(String),
+    /// The content of a file
+    File(PathBuf),
+    /// The output of a shell command
+    Command(String),
+}
+
+impl SecretInput {
+    /// Read the secret value, without its trailing newline
+    pub fn read(&self, key: &str) -> Result<String> {
+        let value = match self {
+            SecretInput::Stdin if io::stdin().is_terminal() => prompt(key)?,
+            SecretInput::Stdin => {
+                let mut value = String::new();
+                io::stdin().read_to_string(&mut value)?;
+                value
+            }
+            SecretInput::Value(value) => value.clone(),
+            SecretInput::File(path) => fs::read_to_string(path)
+                .map_err(|err| anyhow!("Cannot read secret from {:?} : {}", path, err))?,
+            SecretInput::Command(command) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .map_err(|err| anyhow!("Failed to run `{}` : {}", command, err))?;
+
+                if !output.status.success() {
+                    return Err(anyhow!("`{}` failed: {}", command, output.status));
+                }
+
+                String::from_utf8(output.stdout)?
+            }
+        };
+
+        let value = value
+            .strip_suffix('\n')
+            .map(|value| value.strip_suffix('\r').unwrap_or(value))
+            .unwrap_or(&value);
+
+        if value.is_empty() {
+            return Err(anyhow!("Empty value for secret `{}`", key));
+        }
+
+        Ok(value.to_string())
+    }
+}
+
+// Read a line from the terminal with echo disabled
+fn prompt(key: &str) -> Result<String> {
+    eprint!("Value for {key} (input is hidden): ");
+    io::stderr().flush()?;
+
+    let hidden = HiddenInput::new()?;
+    let mut value = String::new();
+    let read = io::stdin().lock().read_line(&mut value);
+    drop(hidden);
+    eprintln!();
+
+    read?;
+    Ok(value)
+}
+
+// Terminal settings before echo was disabled, read by the SIGINT handler
+static TERMINAL_SETTINGS: OnceLock<libc::termios> = OnceLock::new();
+
+// Disables echo on the terminal until dropped, echo is restored on Ctrl-C as well
+struct HiddenInput {
+    previous_handler: libc::sighandler_t,
+}
+
+impl HiddenInput {
+    fn new() -> Result<Self> {
+        let mut settings = unsafe { mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut settings) } != 0 {
+            return Err(anyhow!(
+                "Cannot read terminal settings : {}",
+                io::Error::last_os_error()
+            ));
+        }
+
+        let settings = *TERMINAL_SETTINGS.get_or_init(|| settings);
+        let handler = restore_on_interrupt as extern "C" fn(libc::c_int);
+        let previous_handler = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+        let hidden = HiddenInput { previous_handler };
+
+        let mut no_echo = settings;
+        no_echo.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &no_echo) } != 0 {
+            return Err(anyhow!(
+                "Cannot disable terminal echo : {}",
+                io::Error::last_os_error()
+            ));
+        }
+
+        Ok(hidden)
+    }
+}
+
+impl Drop for HiddenInput {
+    fn drop(&mut self) {
+        restore_terminal();
+        unsafe { libc::signal(libc::SIGINT, self.previous_handler) };
+    }
+}
+
+fn restore_terminal() {
+    if let Some(settings) = TERMINAL_SETTINGS.get() {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, settings) };
+    }
+}
+
+extern "C" fn restore_on_interrupt(signal: libc::c_int) {
+    restore_terminal();
+
+    // Let the default handler terminate the process
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::SecretInput;
+    use anyhow::Result;
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[sealed_test]
+    fn should_read_secret_from_file_and_command() -> Result<()> {
+        // Arrange
+        fs::write("token", "hunter2\n")?;
+
+        // Act
+        let from_file = SecretInput::File(PathBuf::from("token")).read("token")?;
+        let from_command = SecretInput::Command("echo hunter3".to_string()).read("token")?;
+        let failing = SecretInput::Command("false".to_string()).read("token");
+
+        // Assert
+        assert_that!(from_file).is_equal_to("hunter2".to_string());
+        assert_that!(from_command).is_equal_to("hunter3".to_string());
+        assert_that!(failing).is_err();
+        Ok(())
+    }
+}
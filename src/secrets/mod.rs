@@ -0,0 +1,558 @@
+use crate::secrets::command::CommandBackend;
+use crate::secrets::pass::Pass;
+use crate::settings::GPG;
+use crate::Gpg;
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::{Mutex, PoisonError};
+use tera::{Map, Value};
+
+mod age;
+mod command;
+mod input;
+mod pass;
+mod var_file;
+
+pub(crate) use age::Age;
+pub use input::SecretInput;
+pub(crate) use var_file::VarFile;
+
+/// Reserved key of a vars file selecting the backend of all its secrets
+pub(crate) const BACKEND_KEY: &str = "secrets_backend";
+
+/// Reserved key of a vars file holding all its secrets in a single encrypted message
+pub(crate) const BLOB_KEY: &str = "secrets_blob";
+
+lazy_static! {
+    // Decrypted secrets keyed by ciphertext
+    static ref DECRYPTED: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Encrypt and decrypt secret values, `key` is the name of the secret in `[secrets]`
+pub(crate) trait SecretBackend {
+    /// Returns the value to store in the vars file
+    fn encrypt(&self, key: &str, value: &str) -> Result<String>;
+
+    /// Returns the plaintext secret from its stored value
+    fn decrypt(&self, key: &str, stored: &str) -> Result<String>;
+
+    /// Whether all the secrets of a file can be stored encrypted in a single value
+    fn supports_blob(&self) -> bool {
+        true
+    }
+}
+
+/// A secret backend, either declared for a whole vars file with `secrets_backend`
+/// or for a single secret
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackendSettings {
+    /// Encrypt with the `gpg` binary, using `gpg_user_id` unless `user_id` is set
+    Gpg {
+        #[serde(default)]
+        user_id: Option<String>,
+        /// Other users able to decrypt, added to `gpg_recipients`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        recipients: Vec<String>,
+    },
+    /// Encrypt with the `age` binary
+    Age {
+        /// Identity file used to decrypt
+        #[serde(default)]
+        identity: Option<PathBuf>,
+        /// Public keys to encrypt to
+        #[serde(default)]
+        recipients: Vec<String>,
+    },
+    /// Read secrets from the `pass` password store, the stored value is the entry name
+    Pass,
+    /// Read secrets from the output of a command, `{{ key }}` and `{{ value }}` are available
+    /// as quoted shell words
+    Command { command: String },
+}
+
+/// A secret declared with its own backend, the backend fields are inlined with the stored value:
+/// `token = { type = "pass", value = "github/token" }`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct SecretEntry {
+    #[serde(flatten)]
+    pub backend: BackendSettings,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub value: String,
+    /// The value is a whole `[secrets]` table encrypted at once
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub blob: bool,
+}
+
+impl BackendSettings {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            BackendSettings::Gpg { .. } => "gpg",
+            BackendSettings::Age { .. } => "age",
+            BackendSettings::Pass => "pass",
+            BackendSettings::Command { .. } => "command",
+        }
+    }
+
+    /// The same backend encrypting to other recipients, `None` if it does not encrypt.
+    /// With gpg, the first recipient is the `user_id` and the other ones extra `recipients`.
+    pub(crate) fn with_recipients(&self, recipients: &[String]) -> Option<BackendSettings> {
+        match self {
+            BackendSettings::Gpg { .. } => Some(BackendSettings::Gpg {
+                user_id: recipients.first().cloned(),
+                recipients: recipients.iter().skip(1).cloned().collect(),
+            }),
+            BackendSettings::Age { identity, .. } => Some(BackendSettings::Age {
+                identity: identity.clone(),
+                recipients: recipients.to_vec(),
+            }),
+            BackendSettings::Pass | BackendSettings::Command { .. } => None,
+        }
+    }
+
+    pub(crate) fn backend(&self) -> Result<Box<dyn SecretBackend>> {
+        Ok(match self {
+            BackendSettings::Gpg {
+                user_id,
+                recipients,
+            } => Box::new(gpg(user_id.as_deref(), recipients)?),
+            BackendSettings::Age {
+                identity,
+                recipients,
+            } => Box::new(Age::new(identity.clone(), recipients.clone())),
+            BackendSettings::Pass => Box::new(Pass),
+            BackendSettings::Command { command } => Box::new(CommandBackend::new(command)),
+        })
+    }
+}
+
+// Gpg encrypting to `user_id` and `recipients` only, so reencrypting can drop a recipient.
+// Without a user id, `gpg_user_id` and `gpg_recipients` from the settings are used.
+fn gpg(user_id: Option<&str>, recipients: &[String]) -> Result<Gpg> {
+    let gpg = match user_id {
+        Some(user_id) => Gpg::new(user_id),
+        None => GPG
+            .clone()
+            .ok_or_else(|| anyhow!("Cannot use gpg secrets, no GPG user id configured"))?,
+    };
+
+    Ok(gpg.with_recipients(recipients))
+}
+
+/// Read the backend declared in a vars file, if any
+pub(crate) fn file_backend(vars: &Value) -> Result<Option<BackendSettings>> {
+    vars.get(BACKEND_KEY)
+        .map(|backend| {
+            serde_json::from_value(backend.clone())
+                .map_err(|err| anyhow!("Invalid `{}` : {}", BACKEND_KEY, err))
+        })
+        .transpose()
+}
+
+/// Remove the `secrets_backend` and `secrets_blob` keys of a vars file and attach the file
+/// backend to its plain secrets, so the backend is not lost once vars files are merged
+pub(crate) fn resolve_file_backend(vars: &mut Value, path: &Path) -> Result<()> {
+    let backend = file_backend(vars)?;
+    let Some(vars) = vars.as_object_mut() else {
+        return Ok(());
+    };
+
+    vars.remove(BACKEND_KEY);
+    let blob = match vars.remove(BLOB_KEY) {
+        None => None,
+        Some(Value::String(stored)) => Some(SecretEntry {
+            backend: backend.clone().unwrap_or(BackendSettings::Gpg {
+                user_id: None,
+                recipients: vec![],
+            }),
+            value: stored,
+            blob: true,
+        }),
+        Some(_) => return Err(anyhow!("`{}` must be an encrypted string", BLOB_KEY)),
+    };
+
+    if let Some(blob) = blob {
+        let secrets = vars
+            .entry("secrets")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Some(secrets) = secrets.as_object_mut() {
+            // Each file gets its own entry, blobs are not overridden when merging vars
+            let key = format!("{}:{}", BLOB_KEY, path.display());
+            secrets.insert(key, serde_json::to_value(blob)?);
+        }
+    }
+
+    let Some(backend) = backend else {
+        return Ok(());
+    };
+
+    let Some(secrets) = vars.get_mut("secrets").and_then(Value::as_object_mut) else {
+        return Ok(());
+    };
+
+    for secret in secrets.values_mut() {
+        if let Value::String(stored) = secret {
+            let entry = SecretEntry {
+                backend: backend.clone(),
+                value: stored.clone(),
+                blob: false,
+            };
+            *secret = serde_json::to_value(entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Decrypt a single secret, plain string values use the default gpg backend.
+/// Decrypted values are cached by ciphertext for the rest of the run.
+pub(crate) fn decrypt(key: &str, secret: &Value) -> Result<String> {
+    let cache_key = match secret {
+        Value::String(stored) => stored.clone(),
+        // Entries without ciphertext (pass, commands) depend on the secret key
+        _ => format!("{key}:{secret}"),
+    };
+
+    // The lock is held while decrypting, so concurrent renders prompt for a passphrase only once
+    let mut cache = DECRYPTED.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(decrypted) = cache.get(&cache_key) {
+        return Ok(decrypted.clone());
+    }
+
+    let decrypted = match parse_entry(key, secret)? {
+        None => {
+            let Some(gpg) = GPG.as_ref() else {
+                return Err(anyhow!("Cannot decrypt secrets, no GPG user id configured"));
+            };
+
+            SecretBackend::decrypt(gpg, key, secret.as_str().unwrap_or_default())?
+        }
+        Some(entry) => entry.backend.backend()?.decrypt(key, &entry.value)?,
+    };
+
+    cache.insert(cache_key, decrypted.clone());
+    Ok(decrypted)
+}
+
+/// Decrypt every secret of a `[secrets]` table, blobs are expanded to the secrets they contain
+pub(crate) fn decrypt_all(secrets: &Map<String, Value>) -> Result<Map<String, Value>> {
+    let mut decrypted = Map::new();
+    for (key, secret) in secrets {
+        let plaintext = decrypt(key, secret)?;
+        if parse_entry(key, secret)?.is_some_and(|entry| entry.blob) {
+            let blob: Map<String, Value> = toml::from_str(&plaintext)
+                .map_err(|err| anyhow!("Invalid secrets in `{}` : {}", key, err))?;
+            decrypted.extend(blob);
+        } else {
+            decrypted.insert(key.clone(), Value::String(plaintext));
+        }
+    }
+
+    Ok(decrypted)
+}
+
+// Plain strings are gpg secrets, tables declare their own backend
+fn parse_entry(key: &str, secret: &Value) -> Result<Option<SecretEntry>> {
+    match secret {
+        Value::String(_) => Ok(None),
+        Value::Object(_) => serde_json::from_value(secret.clone())
+            .map(Some)
+            .map_err(|err| anyhow!("Invalid secret `{}` : {}", key, err)),
+        _ => Err(anyhow!(
+            "Secret `{}` must be an encrypted string or a table with a backend `type`",
+            key
+        )),
+    }
+}
+
+/// Encrypt a secret with the given backend and add it to the `[secrets]` of a vars file.
+/// With `blob`, or if the file already has one, secrets are stored in `secrets_blob` instead
+/// and the ones encrypted individually with the same backend are moved to it.
+pub(crate) fn push_secret(
+    backend: &dyn SecretBackend,
+    key: &str,
+    value: &str,
+    var_file: &Path,
+    blob: bool,
+) -> Result<()> {
+    let mut file = VarFile::open(var_file)?;
+    file.set(backend, key, value, blob)?;
+    file.save()?;
+    println!("Added secret {} to {:?}", key, var_file);
+
+    Ok(())
+}
+
+/// Directory receiving rendered files holding secrets when `runtime_dir` is set,
+/// it is usually a tmpfs cleared on logout
+pub(crate) fn runtime_dir() -> Result<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_dir())
+        .map(|dir| dir.join("bombadil"))
+        .ok_or_else(|| anyhow!("`runtime_dir` is set but $XDG_RUNTIME_DIR is not a directory"))
+}
+
+/// Whether a dot source is a whole file encrypted with gpg or age
+pub(crate) fn is_encrypted_file(path: &Path) -> bool {
+    path.is_file()
+        && matches!(
+            path.extension().and_then(OsStr::to_str),
+            Some("gpg" | "age")
+        )
+}
+
+/// Decrypt a whole `.gpg` or `.age` file, age files need an identity
+pub(crate) fn decrypt_file(path: &Path, age_identity: Option<&Path>) -> Result<Vec<u8>> {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("age") => Age::new(age_identity.map(Path::to_path_buf), vec![]).decrypt_file(path),
+        _ => Gpg::decrypt_file(path),
+    }
+    .map_err(|err| anyhow!("Cannot decrypt {:?} : {}", path, err))
+}
+
+/// Open a secret in `$VISUAL` or `$EDITOR` and return the edited value. The plaintext is written
+/// to a private directory, under `$XDG_RUNTIME_DIR` when available, and removed afterward.
+pub(crate) fn edit_in_editor(plaintext: &str) -> Result<String> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_dir())
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("bombadil-secret-{}", process::id()));
+
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let path = dir.join("secret");
+    let edited = (|| {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?
+            .write_all(plaintext.as_bytes())?;
+
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{editor} \"$1\""))
+            .arg("sh")
+            .arg(&path)
+            .status()
+            .map_err(|err| anyhow!("Failed to run `{}` : {}", editor, err))?;
+
+        if !status.success() {
+            return Err(anyhow!("`{}` exited with {}", editor, status));
+        }
+
+        Ok(fs::read_to_string(&path)?)
+    })();
+
+    fs::remove_dir_all(&dir)?;
+
+    // Editors usually add a final newline
+    let edited = edited?;
+    Ok(edited.strip_suffix('\n').unwrap_or(&edited).to_string())
+}
+
+/// Run a command with the content of a file as stdin and return its raw output
+pub(crate) fn pipe_file(command: &mut Command, input: &Path) -> Result<Vec<u8>> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .stdin(File::open(input)?)
+        .stdout(Stdio::piped())
+        .output()
+        .map_err(|err| {
+            anyhow!(
+                "error calling {} command, is {} installed ? {}",
+                program,
+                program,
+                err
+            )
+        })?;
+
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(anyhow!("`{}` failed: {}", program, output.status))
+    }
+}
+
+// Write `input` to the command stdin and return its stdout
+pub(crate) fn pipe(command: &mut Command, input: &str) -> Result<String> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| {
+            anyhow!(
+                "error calling {} command, is {} installed ? {}",
+                program,
+                program,
+                err
+            )
+        })?;
+
+    let result = {
+        let stdin = child.stdin.as_mut().unwrap();
+        stdin.write_all(input.as_bytes())
+    };
+
+    let output = child.wait_with_output()?;
+    result?;
+
+    if output.status.success() {
+        Ok(String::from_utf8(output.stdout)?)
+    } else {
+        Err(anyhow!("`{}` failed: {}", program, output.status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::{
+        decrypt_all, file_backend, gpg, push_secret, resolve_file_backend, BackendSettings,
+        SecretEntry,
+    };
+    use crate::templating::Variables;
+    use crate::test_helpers::{setup, setup_secret_backends};
+    use anyhow::Result;
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::fs;
+    use std::path::Path;
+    use tera::Value;
+
+    #[test]
+    fn should_attach_file_backend_to_plain_secrets() -> Result<()> {
+        // Arrange
+        let mut vars: Value = toml::from_str(indoc::indoc! {r#"
+            secrets_backend = { type = "command", command = "echo {{ value }}" }
+
+            [secrets]
+            token = "abc"
+            github = { type = "pass", value = "github/token" }
+        "#})?;
+
+        // Act
+        resolve_file_backend(&mut vars, Path::new("vars.toml"))?;
+
+        // Assert
+        assert_that!(vars.get("secrets_backend")).is_none();
+        let token: SecretEntry = serde_json::from_value(vars["secrets"]["token"].clone())?;
+        let github: SecretEntry = serde_json::from_value(vars["secrets"]["github"].clone())?;
+        assert_that!(token).is_equal_to(SecretEntry {
+            backend: BackendSettings::Command {
+                command: "echo {{ value }}".to_string(),
+            },
+            value: "abc".to_string(),
+            blob: false,
+        });
+        assert_that!(github.backend).is_equal_to(BackendSettings::Pass);
+        Ok(())
+    }
+
+    #[test]
+    fn should_decrypt_with_command_backend() -> Result<()> {
+        // Arrange
+        let secrets: Value = toml::from_str(indoc::indoc! {r#"
+            token = { type = "command", command = "echo {{ key }}-secret" }
+            api = { type = "command", command = "printf '%s' {{ value }} | tr a-z A-Z", value = "it's $(secret)" }
+        "#})?;
+
+        // Act
+        let decrypted = decrypt_all(secrets.as_object().unwrap())?;
+
+        // Assert
+        assert_that!(decrypted.get("token"))
+            .is_some()
+            .is_equal_to(&Value::String("token-secret".to_string()));
+        assert_that!(decrypted.get("api"))
+            .is_some()
+            .is_equal_to(&Value::String("IT'S $(SECRET)".to_string()));
+        Ok(())
+    }
+
+    #[sealed_test]
+    fn should_decrypt_each_secret_once() -> Result<()> {
+        // Arrange
+        let secrets: Value = toml::from_str(indoc::indoc! {r#"
+            token = { type = "command", command = "echo called >> calls && echo {{ key }}" }
+            api = { type = "command", command = "echo called >> calls && echo {{ key }}" }
+        "#})?;
+        let secrets = secrets.as_object().unwrap();
+
+        // Act
+        decrypt_all(secrets)?;
+        let decrypted = decrypt_all(secrets)?;
+
+        // Assert
+        assert_that!(decrypted.get("api"))
+            .is_some()
+            .is_equal_to(&Value::String("api".to_string()));
+        assert_that!(fs::read_to_string("calls")?).is_equal_to("called\ncalled\n".to_string());
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_simple"], before = setup("dotfiles_simple"))]
+    fn should_only_add_settings_recipients_without_user_id() -> Result<()> {
+        // Arrange
+        fs::write(
+            "dotfiles_simple/bombadil.toml",
+            indoc::indoc! {r#"
+                dotfiles_dir = "dotfiles_simple"
+                gpg_user_id = "me"
+                gpg_recipients = [ "old" ]
+            "#},
+        )?;
+
+        // Act
+        let rotated = gpg(Some("me"), &["new".to_string()])?;
+        let default = gpg(None, &[])?;
+
+        // Assert
+        assert_that!(rotated.user_id).is_equal_to("me".to_string());
+        assert_that!(rotated.recipients).is_equal_to(vec!["new".to_string()]);
+        assert_that!(default.recipients).is_equal_to(vec!["old".to_string()]);
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn should_store_secrets_in_blob() -> Result<()> {
+        // Arrange
+        let var_file = Path::new("dotfiles_with_secret_backends/vars.toml");
+        let vars = Variables::read(var_file)?;
+        let backend = file_backend(&vars.inner)?.unwrap().backend()?;
+
+        // Act
+        push_secret(backend.as_ref(), "wifi", "hunter2", var_file, true)?;
+
+        // Assert
+        let raw: Value = toml::from_str(&fs::read_to_string(var_file)?)?;
+        assert_that!(raw.get("secrets_blob")).is_some();
+        assert_that!(raw["secrets"].get("vpn")).is_none();
+        let secrets = Variables::from_path(var_file)?.get_secrets()?;
+        let mut keys: Vec<String> = secrets.keys().cloned().collect();
+        keys.sort();
+        assert_that!(keys).is_equal_to(vec![
+            "github".to_string(),
+            "registry".to_string(),
+            "vpn".to_string(),
+            "wifi".to_string(),
+        ]);
+        assert_that!(secrets.get("vpn"))
+            .is_some()
+            .is_equal_to(&Value::String("vpn".to_string()));
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use crate::secrets::{pipe, SecretBackend};
+use anyhow::{anyhow, Result};
+use std::process::Command;
+
+/// Secrets kept in the `pass` password store, the stored value is the entry name
+/// and defaults to the secret key
+pub(crate) struct Pass;
+
+impl Pass {
+    fn entry<'a>(key: &'a str, stored: &'a str) -> &'a str {
+        if stored.is_empty() {
+            key
+        } else {
+            stored
+        }
+    }
+}
+
+impl SecretBackend for Pass {
+    fn encrypt(&self, key: &str, value: &str) -> Result<String> {
+        pipe(
+            Command::new("pass").args(["insert", "--multiline", "--force", key]),
+            value,
+        )?;
+        Ok(key.to_string())
+    }
+
+    fn decrypt(&self, key: &str, stored: &str) -> Result<String> {
+        let entry = Pass::entry(key, stored);
+        let output = pipe(Command::new("pass").args(["show", entry]), "")
+            .map_err(|err| anyhow!("Cannot read pass entry `{}` : {}", entry, err))?;
+
+        // By convention the password is the first line of the entry
+        Ok(output.lines().next().unwrap_or_default().to_string())
+    }
+
+    fn supports_blob(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,446 @@
+use crate::secrets::{
+    decrypt, file_backend, parse_entry, resolve_file_backend, BackendSettings, SecretBackend,
+    BACKEND_KEY, BLOB_KEY,
+};
+use crate::Gpg;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tera::{Map, Value};
+use toml_edit::{value, Array, DocumentMut, Item, Table};
+
+/// A vars file whose secrets are edited in place, keeping comments and ordering
+pub(crate) struct VarFile {
+    path: PathBuf,
+    document: DocumentMut,
+}
+
+/// A secret of a vars file, as listed by `bombadil secret list`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct SecretInfo {
+    pub key: String,
+    /// Backend type, `gpg` for plain secrets without `secrets_backend`
+    pub backend: String,
+    /// Stored in the `secrets_blob` of the file
+    pub blob: bool,
+}
+
+/// Who can decrypt the secrets of a vars file
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Recipients {
+    /// Number of gpg encrypted secrets, a blob counts as one
+    pub gpg_secrets: usize,
+    /// Gpg key ids found in the encrypted secrets, with the number of secrets encrypted to them
+    pub gpg: BTreeMap<String, usize>,
+    /// Configured age recipients, they cannot be read from age messages
+    pub age: BTreeSet<String>,
+}
+
+impl VarFile {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| anyhow!("Cannot read var file {:?} : {}", path, err))?;
+        let document = content
+            .parse::<DocumentMut>()
+            .map_err(|err| anyhow!("parse error in {:?} :  {}", path, err))?;
+
+        Ok(VarFile {
+            path: path.to_path_buf(),
+            document,
+        })
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        fs::write(&self.path, self.document.to_string())?;
+        Ok(())
+    }
+
+    /// The `secrets_backend` of this file, gpg if none is declared
+    pub(crate) fn backend_settings(&self) -> Result<BackendSettings> {
+        Ok(
+            file_backend(&self.values()?)?.unwrap_or(BackendSettings::Gpg {
+                user_id: None,
+                recipients: vec![],
+            }),
+        )
+    }
+
+    pub(crate) fn declares_backend(&self) -> bool {
+        self.document.contains_key(BACKEND_KEY)
+    }
+
+    pub(crate) fn has_blob(&self) -> bool {
+        self.document.contains_key(BLOB_KEY)
+    }
+
+    /// List the secrets of the file, decrypting the blob if there is one
+    pub(crate) fn list(&self) -> Result<Vec<SecretInfo>> {
+        let mut secrets = vec![];
+        for (key, secret) in self.resolved_secrets()? {
+            let entry = parse_entry(&key, &secret)?;
+            match entry {
+                Some(entry) if entry.blob => {
+                    let blob = self.read_blob(entry.backend.backend()?.as_ref())?;
+                    secrets.extend(blob.keys().map(|key| SecretInfo {
+                        key: key.clone(),
+                        backend: entry.backend.name().to_string(),
+                        blob: true,
+                    }));
+                }
+                Some(entry) => secrets.push(SecretInfo {
+                    key,
+                    backend: entry.backend.name().to_string(),
+                    blob: false,
+                }),
+                None => secrets.push(SecretInfo {
+                    key,
+                    backend: "gpg".to_string(),
+                    blob: false,
+                }),
+            }
+        }
+
+        Ok(secrets)
+    }
+
+    /// Decrypt a single secret, looking into the blob when it is not in `[secrets]`
+    pub(crate) fn get(&self, key: &str) -> Result<String> {
+        if let Some(secret) = self.resolved_secrets()?.get(key) {
+            return decrypt(key, secret);
+        }
+
+        if self.has_blob() {
+            let backend = self.backend_settings()?.backend()?;
+            if let Some(Value::String(secret)) = self.read_blob(backend.as_ref())?.remove(key) {
+                return Ok(secret);
+            }
+        }
+
+        Err(self.not_found(key))
+    }
+
+    /// Encrypt and store a secret, in the blob when `blob` is set or the file already has one.
+    /// `backend` is the file backend, secrets with their own backend are updated in place.
+    pub(crate) fn set(
+        &mut self,
+        backend: &dyn SecretBackend,
+        key: &str,
+        plaintext: &str,
+        blob: bool,
+    ) -> Result<()> {
+        let is_entry = self
+            .document
+            .get("secrets")
+            .and_then(|secrets| secrets.get(key))
+            .is_some_and(|secret| secret.as_table_like().is_some());
+
+        if is_entry {
+            let secrets = self.resolved_secrets()?;
+            let entry = parse_entry(key, &secrets[key])?.ok_or_else(|| self.not_found(key))?;
+            let encrypted = entry.backend.backend()?.encrypt(key, plaintext)?;
+            self.secrets_table()[key]["value"] = value(encrypted);
+            return Ok(());
+        }
+
+        if blob || self.has_blob() {
+            return self.push_to_blob(backend, key, plaintext);
+        }
+
+        let encrypted = backend.encrypt(key, plaintext)?;
+        self.secrets_table()[key] = value(encrypted);
+        Ok(())
+    }
+
+    /// Remove a secret from `[secrets]` or from the blob
+    pub(crate) fn remove(&mut self, key: &str) -> Result<()> {
+        let removed = self
+            .document
+            .get_mut("secrets")
+            .and_then(Item::as_table_like_mut)
+            .and_then(|secrets| secrets.remove(key));
+
+        if removed.is_some() {
+            return Ok(());
+        }
+
+        if self.has_blob() {
+            let backend = self.backend_settings()?.backend()?;
+            let mut secrets = self.read_blob(backend.as_ref())?;
+            if secrets.remove(key).is_some() {
+                return self.write_blob(backend.as_ref(), &secrets);
+            }
+        }
+
+        Err(self.not_found(key))
+    }
+
+    /// Encrypt every secret again for `recipients`, the file backend and secrets with their
+    /// own gpg or age backend are updated to use them
+    pub(crate) fn reencrypt(&mut self, recipients: &[String]) -> Result<Vec<String>> {
+        let current = self.backend_settings()?;
+        let rotated = current.with_recipients(recipients);
+        let mut reencrypted = vec![];
+
+        for (key, secret) in self.resolved_secrets()? {
+            let entry = parse_entry(&key, &secret)?;
+            let (old, new) = match &entry {
+                Some(entry) if entry.blob => {
+                    let Some(new) = &rotated else { continue };
+                    let secrets = self.read_blob(current.backend()?.as_ref())?;
+                    self.write_blob(new.backend()?.as_ref(), &secrets)?;
+                    reencrypted.extend(secrets.keys().cloned());
+                    continue;
+                }
+                Some(entry) => (
+                    entry.backend.clone(),
+                    entry.backend.with_recipients(recipients),
+                ),
+                None => (current.clone(), rotated.clone()),
+            };
+
+            let Some(new) = new else { continue };
+            let plaintext = old.backend()?.decrypt(&key, stored_value(&secret))?;
+            let encrypted = new.backend()?.encrypt(&key, &plaintext)?;
+
+            // Plain secrets use the file backend, entries carry their own
+            let is_plain = self.secrets_table()[&key].is_str();
+            if is_plain {
+                self.secrets_table()[&key] = value(encrypted);
+            } else {
+                let secret = &mut self.secrets_table()[&key];
+                secret["value"] = value(encrypted);
+                set_recipients(secret, &new);
+            }
+
+            reencrypted.push(key);
+        }
+
+        if let Some(rotated) = &rotated {
+            if self.document.contains_key(BACKEND_KEY) {
+                set_recipients(&mut self.document[BACKEND_KEY], rotated);
+            }
+        }
+
+        Ok(reencrypted)
+    }
+
+    /// Read the gpg key ids of every encrypted secret, and the configured age recipients
+    pub(crate) fn recipients(&self) -> Result<Recipients> {
+        let mut recipients = Recipients::default();
+        for (key, secret) in self.resolved_secrets()? {
+            let backend = match parse_entry(&key, &secret)? {
+                Some(entry) => entry.backend,
+                None => BackendSettings::Gpg {
+                    user_id: None,
+                    recipients: vec![],
+                },
+            };
+
+            match backend {
+                BackendSettings::Gpg { .. } => {
+                    recipients.gpg_secrets += 1;
+                    for key_id in Gpg::key_ids(stored_value(&secret))? {
+                        *recipients.gpg.entry(key_id).or_default() += 1;
+                    }
+                }
+                BackendSettings::Age {
+                    recipients: age, ..
+                } => recipients.age.extend(age),
+                _ => {}
+            }
+        }
+
+        Ok(recipients)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The file as tera values, with the file backend attached to its secrets
+    fn resolved_secrets(&self) -> Result<Map<String, Value>> {
+        let mut values = self.values()?;
+        resolve_file_backend(&mut values, &self.path)?;
+        Ok(values
+            .get_mut("secrets")
+            .and_then(Value::as_object_mut)
+            .map(std::mem::take)
+            .unwrap_or_default())
+    }
+
+    fn values(&self) -> Result<Value> {
+        toml::from_str(&self.document.to_string())
+            .map_err(|err| anyhow!("parse error in {:?} :  {}", self.path, err))
+    }
+
+    fn secrets_table(&mut self) -> &mut Item {
+        if !self.document.contains_key("secrets") {
+            self.document["secrets"] = Item::Table(Table::new());
+        }
+
+        &mut self.document["secrets"]
+    }
+
+    fn read_blob(&self, backend: &dyn SecretBackend) -> Result<Map<String, Value>> {
+        match self.document.get(BLOB_KEY).map(|blob| blob.as_str()) {
+            None => Ok(Map::new()),
+            Some(Some(stored)) => {
+                let plaintext = backend.decrypt(BLOB_KEY, stored)?;
+                toml::from_str(&plaintext)
+                    .map_err(|err| anyhow!("Invalid secrets in `{}` : {}", BLOB_KEY, err))
+            }
+            Some(None) => Err(anyhow!("`{}` must be an encrypted string", BLOB_KEY)),
+        }
+    }
+
+    fn write_blob(
+        &mut self,
+        backend: &dyn SecretBackend,
+        secrets: &Map<String, Value>,
+    ) -> Result<()> {
+        let encrypted = backend.encrypt(BLOB_KEY, &toml::to_string(secrets)?)?;
+        self.document[BLOB_KEY] = value(encrypted);
+        Ok(())
+    }
+
+    // Add a secret to the blob, moving the secrets encrypted one by one with the file backend
+    fn push_to_blob(
+        &mut self,
+        backend: &dyn SecretBackend,
+        key: &str,
+        plaintext: &str,
+    ) -> Result<()> {
+        if !backend.supports_blob() {
+            return Err(anyhow!("This secret backend cannot store `{}`", BLOB_KEY));
+        }
+
+        let mut secrets = self.read_blob(backend)?;
+        let plain_keys: Vec<String> = self
+            .document
+            .get("secrets")
+            .and_then(Item::as_table_like)
+            .map(|table| {
+                table
+                    .iter()
+                    .filter(|(_, secret)| secret.is_str())
+                    .map(|(key, _)| key.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for plain_key in plain_keys {
+            let stored = self.secrets_table()[&plain_key]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            let decrypted = backend.decrypt(&plain_key, &stored)?;
+            secrets.insert(plain_key.clone(), Value::String(decrypted));
+            if let Some(table) = self.secrets_table().as_table_like_mut() {
+                table.remove(&plain_key);
+            }
+        }
+
+        if self
+            .document
+            .get("secrets")
+            .and_then(Item::as_table_like)
+            .is_some_and(|secrets| secrets.is_empty())
+        {
+            self.document.remove("secrets");
+        }
+
+        secrets.insert(key.to_string(), Value::String(plaintext.to_string()));
+        self.write_blob(backend, &secrets)
+    }
+
+    fn not_found(&self, key: &str) -> anyhow::Error {
+        anyhow!("No secret `{}` in {:?}", key, self.path)
+    }
+}
+
+fn stored_value(secret: &Value) -> &str {
+    match secret {
+        Value::String(stored) => stored,
+        _ => secret
+            .get("value")
+            .and_then(Value::as_str)
+            .unwrap_or_default(),
+    }
+}
+
+// Point a backend table, either `secrets_backend` or a secret entry, to new recipients
+fn set_recipients(item: &mut Item, backend: &BackendSettings) {
+    let recipients = match backend {
+        BackendSettings::Gpg {
+            user_id,
+            recipients,
+        } => {
+            if let Some(user_id) = user_id {
+                item["user_id"] = value(user_id.as_str());
+            }
+            recipients
+        }
+        BackendSettings::Age { recipients, .. } => recipients,
+        _ => return,
+    };
+
+    if !recipients.is_empty() {
+        item["recipients"] = value(recipients.iter().collect::<Array>());
+    } else if let Some(table) = item.as_table_like_mut() {
+        table.remove("recipients");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::VarFile;
+    use crate::test_helpers::setup_secret_backends;
+    use anyhow::Result;
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn should_edit_secrets_in_place() -> Result<()> {
+        // Arrange
+        let path = Path::new("dotfiles_with_secret_backends/vars.toml");
+        let mut file = VarFile::open(path)?;
+        let backend = file.backend_settings()?.backend()?;
+
+        // Act
+        file.set(backend.as_ref(), "wifi", "hunter2", false)?;
+        file.remove("registry")?;
+        file.save()?;
+
+        // Assert
+        let content = fs::read_to_string(path)?;
+        assert_that!(content).starts_with("# Secrets shared by every machine\n");
+        assert_that!(content).does_not_contain("registry");
+        assert_that!(content).does_not_contain("hunter2");
+        let file = VarFile::open(path)?;
+        assert_that!(file.get("wifi")?).is_equal_to("hunter2".to_string());
+        assert_that!(file.get("github")?).is_equal_to("ghp_token".to_string());
+        assert_that!(VarFile::open(path)?.remove("unknown")).is_err();
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_with_secret_backends"], before = setup_secret_backends())]
+    fn should_reencrypt_for_new_recipient() -> Result<()> {
+        // Arrange
+        let path = Path::new("dotfiles_with_secret_backends/vars.toml");
+        let mut file = VarFile::open(path)?;
+
+        // Act
+        let reencrypted = file.reencrypt(&["age1rotated".to_string()])?;
+        file.save()?;
+
+        // Assert
+        assert_that!(reencrypted).is_equal_to(vec!["vpn".to_string()]);
+        let content = fs::read_to_string(path)?;
+        assert_that!(content).contains(r#"recipients = ["age1rotated"]"#);
+        assert_that!(VarFile::open(path)?.get("vpn")?).is_equal_to("vpn".to_string());
+        Ok(())
+    }
+}
@@ -1,12 +1,15 @@
 use crate::dots::DotVar;
+use crate::hook::Hook;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::path::PathBuf;
 
 /// Represent a link between a `source` dotfile in the user defined dotfiles directory
 /// and the XDG `target` path where it should be linked
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Dot {
-    /// Path relative to user-defined dotfile
+    /// Path relative to user-defined dotfile, or to the repository root for external dots
+    #[serde(default)]
     pub source: PathBuf,
     /// Target path either relative to $HOME or absolute
     pub target: PathBuf,
@@ -21,6 +24,60 @@ pub struct Dot {
     /// Templating enabled for this dot
     #[serde(default)]
     pub direct: bool,
+    /// Hook commands to run after linking, only when the dot was created or updated
+    #[serde(default)]
+    pub posthooks: Vec<Hook>,
+    /// Manage the systemd user units contained in this dot
+    #[serde(default)]
+    pub systemd: Option<SystemdUnits>,
+    /// External git repository to take the dot source from, cloned under `.dots/external`
+    #[serde(default)]
+    pub git: Option<String>,
+    /// Revision of the external repository to checkout, its default branch otherwise
+    #[serde(default)]
+    pub rev: Option<String>,
+    /// Where the dot, or its latest source or target override, was declared
+    #[serde(skip)]
+    pub origin: DotOrigin,
+}
+
+/// The settings file or profile a dot comes from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DotOrigin {
+    /// The main `bombadil.toml`
+    #[default]
+    Settings,
+    /// An imported settings file
+    Import(PathBuf),
+    /// A profile, either creating the dot or overriding its source or target
+    Profile(String),
+}
+
+impl fmt::Display for DotOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DotOrigin::Settings => write!(f, "bombadil.toml"),
+            DotOrigin::Import(path) => write!(f, "import {path:?}"),
+            DotOrigin::Profile(profile) => write!(f, "profile `{profile}`"),
+        }
+    }
+}
+
+impl Default for Dot {
+    fn default() -> Self {
+        Dot {
+            source: PathBuf::default(),
+            target: PathBuf::default(),
+            ignore: vec![],
+            vars: Dot::default_vars(),
+            direct: false,
+            posthooks: vec![],
+            systemd: None,
+            git: None,
+            rev: None,
+            origin: DotOrigin::Settings,
+        }
+    }
 }
 
 /// Same as dot but source and target are optionals
@@ -37,4 +94,24 @@ pub struct DotOverride {
     pub vars: Option<PathBuf>,
     /// Templating enabled for this dot
     pub direct: Option<bool>,
+    /// Hook commands to run after linking, only when the dot was created or updated
+    pub posthooks: Option<Vec<Hook>>,
+    /// Manage the systemd user units contained in this dot
+    pub systemd: Option<SystemdUnits>,
+    /// External git repository to take the dot source from
+    pub git: Option<String>,
+    /// Revision of the external repository to checkout
+    pub rev: Option<String>,
+}
+
+/// What to do with systemd user units once their rendered content changed,
+/// the user manager is always reloaded
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SystemdUnits {
+    /// Enable changed units
+    #[serde(default)]
+    pub enable: bool,
+    /// Restart changed units, starting them if they are not running
+    #[serde(default)]
+    pub start: bool,
 }
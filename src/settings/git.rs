@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+/// Settings for the git operations on the dotfiles repository
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct GitSettings {
+    /// Try the `IdentityFile` entries of `~/.ssh/config` matching the remote host
+    #[serde(default)]
+    pub ssh_config: bool,
+
+    /// Username used when the remote url does not provide one
+    pub username: Option<String>,
+}
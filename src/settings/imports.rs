@@ -1,3 +1,4 @@
+use crate::settings::dots::DotOrigin;
 use crate::settings::profiles::ActiveProfile;
 use crate::settings::Settings;
 use crate::Profile;
@@ -6,7 +7,7 @@ use colored::Colorize;
 use config::{Config, File};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ImportPath {
@@ -25,12 +26,16 @@ pub struct ImportedSettings {
     /// Paths to merge with the main configuration
     #[serde(default)]
     pub import: Vec<ImportPath>,
+
+    /// Profiles to enable when cloning on a given hostname
+    #[serde(default)]
+    pub hosts: HashMap<String, Vec<String>>,
 }
 
 impl Settings {
-    pub(crate) fn merge_imports(&mut self) -> anyhow::Result<()> {
-        let import_paths: Vec<PathBuf> = self
-            .import
+    /// Resolve import paths, either absolute or relative to the dotfiles directory
+    pub(crate) fn import_paths(&self) -> Vec<PathBuf> {
+        self.import
             .iter()
             .map(|import| import.path.clone())
             .map(|path| {
@@ -40,7 +45,11 @@ impl Settings {
                     self.get_dotfiles_path().unwrap().join(path)
                 }
             })
-            .collect();
+            .collect()
+    }
+
+    pub(crate) fn merge_imports(&mut self) -> anyhow::Result<()> {
+        let import_paths = self.import_paths();
 
         for path in import_paths.iter() {
             if path.exists() {
@@ -51,7 +60,7 @@ impl Settings {
                     .map_err(|err| anyhow!("{} : {}", "Config format error".red(), err));
 
                 match sub_setting {
-                    Ok(sub_settings) => self.merge(sub_settings),
+                    Ok(sub_settings) => self.merge(path, sub_settings),
                     Err(err) => {
                         eprintln!("Error loading settings from : {:?} {}", path, err)
                     }
@@ -68,18 +77,67 @@ impl Settings {
         Ok(())
     }
 
-    fn merge(&mut self, sub_settings: ImportedSettings) {
+    fn merge(&mut self, path: &Path, mut sub_settings: ImportedSettings) {
         self.settings
             .prehooks
             .extend_from_slice(&sub_settings.settings.prehooks);
         self.settings
             .posthooks
             .extend_from_slice(&sub_settings.settings.posthooks);
+        self.settings
+            .pre_unlink
+            .extend_from_slice(&sub_settings.settings.pre_unlink);
+        self.settings
+            .post_unlink
+            .extend_from_slice(&sub_settings.settings.post_unlink);
+        self.settings
+            .on_first_install
+            .extend_from_slice(&sub_settings.settings.on_first_install);
+        self.settings
+            .on_profile_change
+            .extend_from_slice(&sub_settings.settings.on_profile_change);
         self.settings
             .vars
             .extend_from_slice(&sub_settings.settings.vars);
+        if self.settings.jobs.is_none() {
+            self.settings.jobs = sub_settings.settings.jobs;
+        }
+        if self.settings.watch.notify.is_none() {
+            self.settings.watch.notify = sub_settings.settings.watch.notify;
+        }
+        self.settings
+            .watch
+            .paths
+            .extend_from_slice(&sub_settings.settings.watch.paths);
+        let packages = &mut self.settings.packages;
+        let sub_packages = sub_settings.settings.packages;
+        if packages.installer.is_none() {
+            packages.installer = sub_packages.installer;
+        }
+        if packages.probe.is_none() {
+            packages.probe = sub_packages.probe;
+        }
+        packages.list.extend(sub_packages.list);
+        let git = &mut self.settings.git;
+        git.ssh_config |= sub_settings.settings.git.ssh_config;
+        if git.username.is_none() {
+            git.username = sub_settings.settings.git.username;
+        }
+        self.settings.secrets.blob |= sub_settings.settings.secrets.blob;
+        let secrets = &mut self.settings.secrets;
+        secrets.runtime_dir |= sub_settings.settings.secrets.runtime_dir;
+        if secrets.age_identity.is_none() {
+            secrets.age_identity = sub_settings.settings.secrets.age_identity;
+        }
+        secrets
+            .age_recipients
+            .extend(sub_settings.settings.secrets.age_recipients);
         self.import.extend_from_slice(&sub_settings.import);
+        for dot in sub_settings.settings.dots.values_mut() {
+            dot.origin = DotOrigin::Import(path.to_path_buf());
+        }
         self.settings.dots.extend(sub_settings.settings.dots);
         self.profiles.extend(sub_settings.profiles);
+        self.hosts.extend(sub_settings.hosts);
     }
 }
@@ -12,8 +12,12 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 
 pub mod dots;
+pub mod git;
 pub mod imports;
+pub mod packages;
 pub mod profiles;
+pub mod secrets;
+pub mod watch;
 
 lazy_static! {
     pub static ref SETTINGS: Settings = Settings::get().unwrap_or_default();
@@ -21,7 +25,7 @@ lazy_static! {
         SETTINGS
             .gpg_user_id
             .as_ref()
-            .map(|gpg| Gpg::new(gpg.as_str()))
+            .map(|gpg| Gpg::new(gpg.as_str()).with_recipients(&SETTINGS.gpg_recipients))
     };
 }
 
@@ -48,6 +52,10 @@ pub struct Settings {
 
     pub gpg_user_id: Option<String>,
 
+    /// Other gpg users secrets are encrypted to, along with `gpg_user_id`
+    #[serde(default)]
+    pub gpg_recipients: Vec<String>,
+
     #[serde(default)]
     pub settings: ActiveProfile,
 
@@ -57,6 +65,10 @@ pub struct Settings {
     /// Paths to merge with the main configuration
     #[serde(default)]
     pub import: Vec<ImportPath>,
+
+    /// Profiles to enable when cloning on a given hostname
+    #[serde(default)]
+    pub hosts: HashMap<String, Vec<String>>,
 }
 
 impl Settings {
@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// Packages the dotfiles depend on and how to install them
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct PackageSettings {
+    /// Installer command template, missing packages are available in the `packages` variable,
+    /// e.g. `pacman -S --needed {{ packages }}`
+    pub installer: Option<String>,
+
+    /// Command template checking whether a single `package` is installed, `which {{ package }}` by default
+    pub probe: Option<String>,
+
+    /// Packages to install
+    #[serde(default)]
+    pub list: Vec<String>,
+}
@@ -1,5 +1,10 @@
+use crate::hook::Hook;
 use crate::settings::dots::Dot;
 use crate::settings::dots::DotOverride;
+use crate::settings::git::GitSettings;
+use crate::settings::packages::PackageSettings;
+use crate::settings::secrets::SecretSettings;
+use crate::settings::watch::WatchSettings;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -13,15 +18,50 @@ pub struct ActiveProfile {
 
     /// Post install hook commands
     #[serde(default)]
-    pub prehooks: Vec<String>,
+    pub prehooks: Vec<Hook>,
 
     /// Post install hook commands
     #[serde(default)]
-    pub posthooks: Vec<String>,
+    pub posthooks: Vec<Hook>,
+
+    /// Hook commands run before removing dot symlinks
+    #[serde(default)]
+    pub pre_unlink: Vec<Hook>,
+
+    /// Hook commands run after removing dot symlinks
+    #[serde(default)]
+    pub post_unlink: Vec<Hook>,
+
+    /// Hook commands run after the first install, when no previous state exists
+    #[serde(default)]
+    pub on_first_install: Vec<Hook>,
+
+    /// Hook commands run after an install enabling different profiles than the previous one
+    #[serde(default)]
+    pub on_profile_change: Vec<Hook>,
 
     /// Variables to use in templates
     #[serde(default)]
     pub vars: Vec<PathBuf>,
+
+    /// Number of threads rendering dots, defaults to the number of CPUs
+    pub jobs: Option<usize>,
+
+    /// Watch mode settings
+    #[serde(default)]
+    pub watch: WatchSettings,
+
+    /// Package dependencies and installer
+    #[serde(default)]
+    pub packages: PackageSettings,
+
+    /// Git authentication settings
+    #[serde(default)]
+    pub git: GitSettings,
+
+    /// Secret storage settings
+    #[serde(default)]
+    pub secrets: SecretSettings,
 }
 
 /// An named profile meant to override the default one
@@ -37,13 +77,33 @@ pub struct Profile {
 
     /// Pre install hook commands
     #[serde(default)]
-    pub prehooks: Vec<String>,
+    pub prehooks: Vec<Hook>,
 
     /// Post install hook commands
     #[serde(default)]
-    pub posthooks: Vec<String>,
+    pub posthooks: Vec<Hook>,
+
+    /// Hook commands run before removing dot symlinks
+    #[serde(default)]
+    pub pre_unlink: Vec<Hook>,
+
+    /// Hook commands run after removing dot symlinks
+    #[serde(default)]
+    pub post_unlink: Vec<Hook>,
+
+    /// Hook commands run after the first install, when no previous state exists
+    #[serde(default)]
+    pub on_first_install: Vec<Hook>,
+
+    /// Hook commands run after an install enabling different profiles than the previous one
+    #[serde(default)]
+    pub on_profile_change: Vec<Hook>,
 
     /// Variables to use in templates
     #[serde(default)]
     pub vars: Vec<PathBuf>,
+
+    /// Additional packages to install
+    #[serde(default)]
+    pub packages: Vec<String>,
 }
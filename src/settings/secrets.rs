@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Settings for secret variables
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SecretSettings {
+    /// Store all the secrets of a vars file in a single encrypted message
+    #[serde(default)]
+    pub blob: bool,
+    /// Write rendered files holding secrets to `$XDG_RUNTIME_DIR/bombadil` instead of `.dots`
+    #[serde(default)]
+    pub runtime_dir: bool,
+    /// Age identity decrypting `.age` dot sources
+    pub age_identity: Option<PathBuf>,
+    /// Age recipients of the files encrypted with `bombadil encrypt-file --age`
+    #[serde(default)]
+    pub age_recipients: Vec<String>,
+}
@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Settings for `bombadil watch`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct WatchSettings {
+    /// A shell command run when re-linking fails while watching,
+    /// the error message is available in the `BOMBADIL_ERROR` environment variable
+    pub notify: Option<String>,
+
+    /// Additional paths to watch outside the dotfiles directory, changes to those re-render every dot.
+    /// Either absolute or relative to the dotfiles directory
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+}
@@ -1,11 +1,13 @@
+use crate::dots::ciphertext_hash_path;
 use crate::paths::{unlink, DotPaths};
+use crate::systemd::UnitSnapshot;
 use crate::Bombadil;
 use anyhow::{anyhow, Result};
 use colored::*;
 use config::Config;
 use config::File;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use std::fmt::Debug;
 use std::fs;
 use std::path::PathBuf;
@@ -17,6 +19,15 @@ pub(crate) struct BombadilState {
     #[serde(skip)]
     pub path: PathBuf,
     pub symlinks: HashSet<PathBuf>,
+    /// Profiles enabled during the last install
+    #[serde(default)]
+    pub profiles: Vec<String>,
+    /// Rendered files holding decrypted secrets
+    #[serde(default)]
+    pub secret_renders: BTreeSet<PathBuf>,
+    /// Systemd units managed by the last install
+    #[serde(default)]
+    pub units: BTreeSet<String>,
 }
 
 impl BombadilState {
@@ -44,6 +55,18 @@ impl BombadilState {
         Ok(())
     }
 
+    /// Whether the given profiles differ from the ones enabled during the last install
+    pub fn profiles_changed(&self, profiles: &[String]) -> bool {
+        let previous: HashSet<&String> = self.profiles.iter().collect();
+        let current: HashSet<&String> = profiles.iter().collect();
+        previous != current
+    }
+
+    /// Units managed by the previous install that the `current` state no longer manages
+    pub fn removed_units(&self, current: &BombadilState) -> Vec<String> {
+        self.units.difference(&current.units).cloned().collect()
+    }
+
     pub fn remove_targets(&self) -> Vec<Result<PathBuf>> {
         let mut unlink_results = vec![];
 
@@ -57,6 +80,18 @@ impl BombadilState {
 
         unlink_results
     }
+
+    /// Delete the rendered files holding secrets, returning the removed ones
+    pub fn remove_secret_renders(&self) -> Vec<&PathBuf> {
+        self.secret_renders
+            .iter()
+            .filter(|path| {
+                // Decrypted files also have the hash of their ciphertext next to them
+                let _ = fs::remove_file(ciphertext_hash_path(path));
+                fs::remove_file(path).is_ok()
+            })
+            .collect()
+    }
 }
 
 impl From<&mut Bombadil> for BombadilState {
@@ -73,6 +108,21 @@ impl From<&mut Bombadil> for BombadilState {
             .map(|dot| dot.1.target().unwrap())
             .collect();
 
-        Self { path, symlinks }
+        let profiles = current.profile_enabled.clone();
+
+        let units = current
+            .dots
+            .values()
+            .filter(|dot| dot.systemd.is_some())
+            .flat_map(|dot| UnitSnapshot::take(dot).units())
+            .collect();
+
+        Self {
+            path,
+            symlinks,
+            profiles,
+            secret_renders: BTreeSet::new(),
+            units,
+        }
     }
 }
@@ -0,0 +1,185 @@
+use crate::paths::DotPaths;
+use crate::settings::dots::{Dot, SystemdUnits};
+use colored::Colorize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const UNIT_EXTENSIONS: [&str; 9] = [
+    "service",
+    "socket",
+    "timer",
+    "path",
+    "target",
+    "mount",
+    "automount",
+    "slice",
+    "scope",
+];
+
+/// Content of the unit files linked by a dot, keyed by unit name
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct UnitSnapshot(BTreeMap<String, Vec<u8>>);
+
+impl UnitSnapshot {
+    /// Read the unit files currently reachable through the dot target
+    pub(crate) fn take(dot: &Dot) -> Self {
+        let Ok(target) = dot.target() else {
+            return Self::default();
+        };
+
+        let units = unit_files(&target)
+            .into_iter()
+            .filter_map(|path| {
+                let name = path.file_name()?.to_str()?.to_string();
+                let content = fs::read(&path).ok()?;
+                Some((name, content))
+            })
+            .collect();
+
+        Self(units)
+    }
+
+    /// Units added or modified since the `previous` snapshot
+    pub(crate) fn changed_since(&self, previous: &UnitSnapshot) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(name, content)| previous.0.get(*name) != Some(*content))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub(crate) fn units(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+}
+
+/// Reload the user manager, then enable and restart changed units according to their dot options
+pub(crate) fn apply(changed: &[(String, SystemdUnits)]) {
+    if changed.is_empty() {
+        return;
+    }
+
+    println!("{}", "[Systemd]".bold().yellow());
+    if !systemctl(&["daemon-reload"]) {
+        return;
+    }
+
+    for (unit, options) in changed {
+        if options.enable {
+            systemctl(&["enable", unit]);
+        }
+
+        if options.start {
+            systemctl(&["restart", unit]);
+        }
+    }
+
+    println!();
+}
+
+/// Stop and disable units before their unit files are removed
+pub(crate) fn disable(units: &[String]) {
+    if units.is_empty() {
+        return;
+    }
+
+    let mut args = vec!["disable", "--now"];
+    args.extend(units.iter().map(String::as_str));
+    systemctl(&args);
+}
+
+/// Reload the user manager once unit files have been removed
+pub(crate) fn daemon_reload() {
+    systemctl(&["daemon-reload"]);
+}
+
+// Run `systemctl --user`, a failing command is reported but does not stop the installation.
+// Returns false when systemctl could not be run at all.
+fn systemctl(args: &[&str]) -> bool {
+    let command = format!("systemctl --user {}", args.join(" "));
+    println!("{}", command.green());
+
+    match Command::new("systemctl").arg("--user").args(args).status() {
+        Ok(status) => {
+            if !status.success() {
+                let warning = format!("`{command}` failed: {status}");
+                eprintln!("{}", warning.red());
+            }
+
+            true
+        }
+        Err(err) => {
+            let warning = format!("Failed to run `{command}` : {err}");
+            eprintln!("{}", warning.red());
+            false
+        }
+    }
+}
+
+// A dot is either a single unit file or a directory of unit files
+fn unit_files(target: &Path) -> Vec<PathBuf> {
+    if target.is_dir() {
+        let Ok(entries) = target.read_dir() else {
+            return vec![];
+        };
+
+        let mut units: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && is_unit(path))
+            .collect();
+        units.sort();
+        units
+    } else if target.is_file() && is_unit(target) {
+        vec![target.to_path_buf()]
+    } else {
+        vec![]
+    }
+}
+
+fn is_unit(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| UNIT_EXTENSIONS.contains(&extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::dots::Dot;
+    use crate::systemd::UnitSnapshot;
+    use anyhow::Result;
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::path::PathBuf;
+    use std::{env, fs};
+
+    #[sealed_test]
+    fn should_detect_changed_units() -> Result<()> {
+        // Arrange
+        env::set_var("HOME", env::current_dir()?);
+        fs::create_dir("units")?;
+        fs::write("units/mako.service", "[Service]\nExecStart=mako")?;
+        fs::write("units/backup.timer", "[Timer]\nOnCalendar=daily")?;
+        fs::write("units/README.md", "not a unit")?;
+        let dot = Dot {
+            target: PathBuf::from("units"),
+            ..Default::default()
+        };
+        let before = UnitSnapshot::take(&dot);
+
+        // Act
+        fs::write("units/mako.service", "[Service]\nExecStart=mako --verbose")?;
+        fs::write("units/kanshi.service", "[Service]\nExecStart=kanshi")?;
+        let after = UnitSnapshot::take(&dot);
+
+        // Assert
+        assert_that!(before.units())
+            .is_equal_to(vec!["backup.timer".to_string(), "mako.service".to_string()]);
+        assert_that!(after.changed_since(&before)).is_equal_to(vec![
+            "kanshi.service".to_string(),
+            "mako.service".to_string(),
+        ]);
+        Ok(())
+    }
+}
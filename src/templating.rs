@@ -1,5 +1,4 @@
-use crate::gpg::Gpg;
-use crate::settings::GPG;
+use crate::secrets;
 use anyhow::{anyhow, Result};
 use colored::Colorize;
 use json_value_merge::Merge;
@@ -53,43 +52,13 @@ impl Variables {
             return Ok(Map::new());
         };
 
-        let Some(gpg) = GPG.as_ref() else {
-            return Err(anyhow!("Cannot decrypt secrets, no GPG user id configured"));
-        };
-
-        let mut decrypted_secrets = serde_json::Map::new();
-        for (key, value) in secrets {
-            let decrypted = gpg.decrypt_secret(
-                value
-                    .as_str()
-                    .expect("Secret value mut be a gpg encrypted string"),
-            )?;
-            decrypted_secrets.insert(key.clone(), Value::String(decrypted));
-        }
-
-        Ok(decrypted_secrets)
-    }
-
-    pub(crate) fn push_secret(&mut self, key: &str, encrypted: &str) {
-        match self.get_secrets_mut() {
-            None => {
-                let mut secrets = tera::Map::new();
-                secrets.insert(key.to_string(), Value::String(encrypted.to_string()));
-                let Some(vars) = self.inner.as_object_mut() else {
-                    panic!("Variables should be a Value::Object");
-                };
-
-                vars.insert("secrets".to_string(), Value::Object(secrets));
-            }
-            Some(secrets) => {
-                secrets.insert(key.to_string(), Value::String(encrypted.to_string()));
-            }
-        };
+        secrets::decrypt_all(secrets)
     }
 
     pub(crate) fn from_paths(base_path: &Path, var_paths: &[PathBuf]) -> Result<Self> {
         let mut out = Self::default();
         for path in var_paths {
+            let path = shellexpand::tilde(path.to_string_lossy().as_ref()).to_string();
             let variables = Self::from_path(base_path.join(path))?;
             out.extend(variables);
         }
@@ -97,9 +66,16 @@ impl Variables {
         Ok(out)
     }
 
-    /// Deserialize a toml file struct Variables
+    /// Deserialize a toml file struct Variables, the file secret backend is attached to its secrets
     pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path = path.as_ref();
+        let mut vars = Self::read(path.as_ref())?;
+        secrets::resolve_file_backend(&mut vars.inner, path.as_ref())
+            .map_err(|err| anyhow!("{} in {:?}", err, path.as_ref()))?;
+        Ok(vars)
+    }
+
+    /// Deserialize a toml file as is
+    pub(crate) fn read(path: &Path) -> Result<Self> {
         let file = File::open(path);
 
         if let Err(err) = file {
@@ -116,43 +92,19 @@ impl Variables {
             let variables: tera::Value = toml::from_str(&contents)
                 .map_err(|err| anyhow!("parse error in {:?} :  {}", path, err))?;
 
-            let vars = if let Some(gpg) = GPG.as_ref() {
-                let secrets = variables
-                    .get("secrets")
-                    .and_then(|secrets| secrets.as_object());
-                // Replace secrets with their decrypted values
-                if let Some(secrets) = secrets {
-                    let secrets = Variables::decrypt_values(secrets, gpg)?;
-                    variables.get("secrets").replace(&secrets);
-                };
-
-                Variables { inner: variables }
-            } else {
-                Variables { inner: variables }
-            };
-
-            Ok(vars)
+            Ok(Variables { inner: variables })
         }
     }
 
-    /// Read a file in the given path and return its content
-    /// with variable replaced by their values.
-    pub(crate) fn to_dot(&self, path: &Path) -> tera::Result<String> {
-        // Read file content
-        let file = File::open(path)?;
-        let mut buf_reader = BufReader::new(file);
-        let mut contents = String::new();
-        buf_reader.read_to_string(&mut contents)?;
-
-        // Create the tera context from variables and secrets.
-        let mut context = tera::Context::new();
-        let variable_context = Context::from_serialize(self.inner.clone())?;
-        context.extend(variable_context);
-        let mut tera = Tera::default();
-        let filename = path.as_os_str().to_str().expect("Non UTF8 filename");
-
-        tera.add_raw_template(filename, &contents)?;
-        tera.render(filename, &context)
+    /// Build the tera context from variables and secrets, this is meant to be
+    /// done once per dot and shared across all rendered files.
+    pub(crate) fn to_context(&self) -> tera::Result<Context> {
+        Context::from_serialize(&self.inner)
+    }
+
+    /// Render a template string against the variables
+    pub(crate) fn render_str(&self, template: &str) -> tera::Result<String> {
+        Tera::one_off(template, &self.to_context()?, false)
     }
 
     pub(crate) fn extend(&mut self, other: Variables) {
@@ -167,25 +119,6 @@ impl Variables {
         vars.insert("secrets".to_string(), Value::Object(secrets));
     }
 
-    fn decrypt_values(vars: &Map<String, Value>, gpg: &Gpg) -> Result<Value> {
-        let mut decrypted = tera::Map::new();
-        for (key, value) in vars {
-            let value = value
-                .as_str()
-                .expect("'[secrets]' values must be a encrypted type string");
-            let value = gpg.decrypt_secret(value)?;
-            decrypted.insert(key.clone(), tera::Value::String(value));
-        }
-
-        Ok(Value::Object(decrypted))
-    }
-
-    fn get_secrets_mut(&mut self) -> Option<&mut Map<String, Value>> {
-        self.inner
-            .get_mut("secrets")
-            .and_then(|value| value.as_object_mut())
-    }
-
     pub(crate) fn with_os(mut self) -> Self {
         let Some(vars) = self.inner.as_object_mut() else {
             panic!("Variables should be a Value::Object");
@@ -205,9 +138,45 @@ impl Variables {
     }
 }
 
+/// A rendered template
+pub(crate) struct Rendered {
+    pub(crate) content: String,
+    /// Whether the content depends on decrypted secrets
+    pub(crate) has_secrets: bool,
+}
+
+/// Read a file in the given path and render it against an already built tera context
+pub(crate) fn render(path: &Path, context: &Context) -> tera::Result<Rendered> {
+    // Read file content
+    let file = File::open(path)?;
+    let mut buf_reader = BufReader::new(file);
+    let mut contents = String::new();
+    buf_reader.read_to_string(&mut contents)?;
+
+    let mut tera = Tera::default();
+    let filename = path.as_os_str().to_str().expect("Non UTF8 filename");
+
+    tera.add_raw_template(filename, &contents)?;
+    let content = tera.render(filename, context)?;
+
+    // Templates mentioning secrets are rendered again without them,
+    // the file holds secrets if it fails or renders differently
+    let has_secrets = context.contains_key("secrets") && contents.contains("secrets") && {
+        let mut public_context = context.clone();
+        public_context.remove("secrets");
+        tera.render(filename, &public_context)
+            .map_or(true, |public| public != content)
+    };
+
+    Ok(Rendered {
+        content,
+        has_secrets,
+    })
+}
+
 #[cfg(test)]
 mod test {
-    use crate::templating::Variables;
+    use crate::templating::{render, Variables};
     use anyhow::Result;
     use indoc::indoc;
     use pretty_assertions::assert_eq;
@@ -215,15 +184,20 @@ mod test {
     use speculoos::prelude::*;
     use std::path::Path;
 
+    fn secret(key: &str, value: &str) -> Map<String, Value> {
+        Map::from_iter([(key.to_string(), Value::String(value.to_string()))])
+    }
+
     #[test]
     fn should_inject_variables() {
         let mut variables = Map::new();
         variables.insert("red".to_string(), Value::String("red_value".to_string()));
         let variables = Value::Object(variables);
 
-        let dot = Variables { inner: variables }
-            .to_dot(Path::new("tests/dotfiles_simple/template.css"))
-            .unwrap();
+        let context = Variables { inner: variables }.to_context().unwrap();
+        let dot = render(Path::new("tests/dotfiles_simple/template.css"), &context)
+            .unwrap()
+            .content;
 
         assert_eq!(
             dot,
@@ -245,9 +219,13 @@ mod test {
             inner: Value::Object(variables),
         };
 
-        variables.push_secret("pass", "hunter2");
+        variables.with_secrets(secret("pass", "hunter2"));
 
-        let dot_content = variables.to_dot(Path::new("tests/dotfiles_with_secret/template"))?;
+        let dot_content = render(
+            Path::new("tests/dotfiles_with_secret/template"),
+            &variables.to_context()?,
+        )?
+        .content;
 
         assert_eq!(
             dot_content,
@@ -260,12 +238,36 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn should_detect_rendered_secrets() -> Result<()> {
+        // Arrange
+        let mut variables: Variables = toml::from_str(indoc! {
+            "
+            red = \"red_value\"
+            "
+        })?;
+        variables.with_secrets(secret("pass", "hunter2"));
+        let context = variables.to_context()?;
+
+        // Act
+        let with_secret = render(Path::new("tests/dotfiles_with_secret/template"), &context)?;
+        let without_secret = render(Path::new("tests/dotfiles_simple/template.css"), &context)?;
+
+        // Assert
+        assert_that!(with_secret.has_secrets).is_true();
+        assert_that!(without_secret.has_secrets).is_false();
+        Ok(())
+    }
+
     #[test]
     fn should_fail_on_non_utf8_file() {
-        let content = Variables {
+        let context = Variables {
             inner: Value::Object(Map::new()),
         }
-        .to_dot(Path::new("tests/dotfiles_non_utf8/ferris.png"));
+        .to_context()
+        .unwrap();
+        let content = render(Path::new("tests/dotfiles_non_utf8/ferris.png"), &context)
+            .map(|rendered| rendered.content);
 
         assert_that!(content).is_err();
     }
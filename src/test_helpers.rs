@@ -0,0 +1,63 @@
+use crate::Bombadil;
+use cmd_lib::run_cmd;
+use git2::{IndexAddOption, Oid, Repository, Signature};
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Use the sealed test directory as `$HOME`, with an empty config directory, and return its path
+pub(crate) fn setup_home() -> PathBuf {
+    let home_dir = env::current_dir().unwrap().canonicalize().unwrap();
+    env::set_var("HOME", &home_dir);
+
+    #[cfg(target_os = "macos")]
+    run_cmd!(mkdir -p "Library/Application Support";).unwrap();
+
+    run_cmd!(mkdir .config;).unwrap();
+
+    home_dir
+}
+
+/// Same as [`setup_home`], then link the settings of the `dotfiles` fixture
+pub(crate) fn setup(dotfiles: &str) {
+    setup_home();
+    Bombadil::link_self_config(Some(PathBuf::from(dotfiles))).unwrap();
+}
+
+/// Same as [`setup`] for `dotfiles_with_secret_backends`, with its stub backends on `$PATH`
+pub(crate) fn setup_secret_backends() {
+    setup("dotfiles_with_secret_backends");
+
+    let stubs = env::current_dir()
+        .unwrap()
+        .join("dotfiles_with_secret_backends/bin");
+    let path = env::var("PATH").unwrap();
+    env::set_var("PATH", format!("{}:{}", stubs.display(), path));
+}
+
+/// Commit every file in `path` to a new repository
+pub(crate) fn commit_all(path: &Path) -> Result<Oid, git2::Error> {
+    let repository = Repository::init(path)?;
+    let signature = Signature::now("bombadil", "bombadil@example.org")?;
+    let mut index = repository.index()?;
+    index.add_all(["*"], IndexAddOption::DEFAULT, None)?;
+    let tree = repository.find_tree(index.write_tree()?)?;
+    repository.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+}
+
+/// Commit a file on the current branch of the repository at `path`, leaving its working directory untouched
+pub(crate) fn commit_file(path: &Path, file: &str, content: &str) -> Result<Oid, git2::Error> {
+    let repository = Repository::open(path)?;
+    let signature = Signature::now("bombadil", "bombadil@example.org")?;
+    let parent = repository.head()?.peel_to_commit()?;
+    let mut builder = repository.treebuilder(Some(&parent.tree()?))?;
+    builder.insert(file, repository.blob(content.as_bytes())?, 0o100644)?;
+    let tree = repository.find_tree(builder.write()?)?;
+    repository.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        file,
+        &tree,
+        &[&parent],
+    )
+}
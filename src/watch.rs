@@ -0,0 +1,419 @@
+use crate::settings::Settings;
+use crate::{Bombadil, Mode};
+use anyhow::Result;
+use colored::*;
+use ignore_files::IgnoreFilter;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use watchexec::sources::fs::Watcher;
+use watchexec::Watchexec;
+use watchexec_events::filekind::FileEventKind;
+use watchexec_events::Tag;
+use watchexec_filterer_ignore::IgnoreFilterer;
+
+/// What needs to be re-rendered after some files changed
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Changes {
+    /// `bombadil.toml` or one of its imports changed, settings must be reloaded
+    Settings,
+    /// A global var file changed, every dot needs to be rendered again
+    Vars,
+    /// Only those dots are affected by the changes
+    Dots(HashSet<String>),
+}
+
+impl Bombadil {
+    /// Watch dotfiles and automatically run link on changes
+    pub async fn watch(profiles: Vec<String>, force: bool) -> Result<()> {
+        let mut bombadil = Bombadil::from_settings(Mode::Gpg)?;
+        bombadil.enable_profiles(profiles.iter().map(String::as_str).collect())?;
+
+        let dotfiles_path = &bombadil.dotfiles_absolute_path()?;
+        let watched_paths = bombadil.watched_paths();
+
+        // Ignore stuff like .git dirs
+        let (ignore_files, _) = ignore_files::from_origin(dotfiles_path.as_path()).await;
+        let ignore_filter = IgnoreFilter::new(dotfiles_path, &ignore_files).await?;
+
+        // Keep the current instance around, so we only reload what changed
+        let bombadil = Arc::new(Mutex::new(bombadil));
+
+        let watchexec = Watchexec::new(move |mut action| {
+            // Select only relevant events (creations, modifications, deletions)
+            let changed: Vec<PathBuf> = action
+                .events
+                .iter()
+                .filter(|event| {
+                    event.tags.iter().any(|t| {
+                        matches!(t, &Tag::FileEventKind(FileEventKind::Create(_)))
+                            || matches!(t, &Tag::FileEventKind(FileEventKind::Modify(_)))
+                            || matches!(t, &Tag::FileEventKind(FileEventKind::Remove(_)))
+                    })
+                })
+                .flat_map(|event| event.paths().map(|(path, _)| path.to_path_buf()))
+                .collect();
+
+            if !changed.is_empty() {
+                let mut b = bombadil.lock().expect("Bombadil lock poisoned");
+                // Errors are reported, and we keep watching so the next change can fix them
+                if let Err(err) = b.on_changes(&changed, &profiles, force) {
+                    b.report_error(&err);
+                }
+            }
+
+            if action.signals().next().is_some() {
+                eprintln!("[Quitting...]");
+                action.quit();
+            }
+
+            action
+        })?;
+
+        watchexec
+            .config
+            .filterer(IgnoreFilterer(ignore_filter))
+            .throttle(Duration::from_secs(1))
+            .file_watcher(Watcher::Native)
+            .pathset(watched_paths);
+
+        watchexec.main().await??;
+        Ok(())
+    }
+
+    // Reload and re-link whatever is affected by the changed paths,
+    // on error the current instance is kept so we can retry on the next change
+    fn on_changes(&mut self, changed: &[PathBuf], profiles: &[String], force: bool) -> Result<()> {
+        match self.changes(changed) {
+            Changes::Settings => {
+                println!("{}", "Settings changed, reloading ...".green());
+                let mut bombadil = Bombadil::from_settings(Mode::Gpg)?;
+                bombadil.enable_profiles(profiles.iter().map(String::as_str).collect())?;
+                if bombadil.jobs == self.jobs {
+                    bombadil.render_pool = self.render_pool.clone();
+                }
+                *self = bombadil;
+                self.install(force)
+            }
+            Changes::Vars => {
+                println!("{}", "Variables changed, re-linking dots ...".green());
+                self.reload_vars()?;
+                self.install(force)
+            }
+            Changes::Dots(dots) if !dots.is_empty() => {
+                let mut keys: Vec<&str> = dots.iter().map(String::as_str).collect();
+                keys.sort();
+                let message = format!("Detected changes in {}, re-linking ...", keys.join(", "));
+                println!("{}", message.green());
+                self.install_dots(&dots, force)
+            }
+            Changes::Dots(_) => Ok(()),
+        }
+    }
+
+    // Print the error and send it to the notification command if any
+    fn report_error(&self, err: &anyhow::Error) {
+        let message = format!("Failed to re-link dots: {err}");
+        eprintln!("{}", message.red());
+
+        let Some(command) = &self.watch.notify else {
+            return;
+        };
+
+        let status = Command::new("sh")
+            .args(["-c", command])
+            .env("BOMBADIL_ERROR", &message)
+            .status();
+
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => eprintln!("Notification command failed with status {status}"),
+            Err(err) => eprintln!("Failed to run notification command: {err}"),
+        }
+    }
+
+    /// Paths to watch, the dotfiles directory and everything outside of it the dots depend on:
+    /// `settings.watch.paths`, var files and imported settings.
+    pub(crate) fn watched_paths(&self) -> Vec<PathBuf> {
+        let dotfiles_path = normalize(&self.path);
+        let mut watched = vec![dotfiles_path.clone()];
+
+        let dependencies = self
+            .watch
+            .paths
+            .iter()
+            .chain(self.var_paths.iter())
+            .chain(self.imports.iter())
+            .map(|path| normalize(&self.resolve_path(path)))
+            .chain(Settings::bombadil_config_xdg_path().map(|path| normalize(&path)))
+            .filter(|path| !path.starts_with(&dotfiles_path));
+
+        for path in dependencies {
+            // Watch the parent directory of files, so we still get
+            // notified when they are replaced rather than modified
+            let path = match path.parent() {
+                Some(parent) if !path.is_dir() => parent.to_path_buf(),
+                _ => path,
+            };
+
+            if !path.exists() {
+                let warning = format!("Cannot watch {path:?}, no such file or directory");
+                eprintln!("{}", warning.yellow());
+            } else if !watched.iter().any(|watched| path.starts_with(watched)) {
+                watched.push(path);
+            }
+        }
+
+        watched
+    }
+
+    // Expand `~` and resolve relative paths against the dotfiles directory
+    fn resolve_path(&self, path: &Path) -> PathBuf {
+        let path = path.to_string_lossy();
+        let path = shellexpand::tilde(path.as_ref());
+        self.path.join(path.as_ref())
+    }
+
+    /// Map changed paths to what needs to be reloaded
+    pub(crate) fn changes(&self, changed: &[PathBuf]) -> Changes {
+        let changed: Vec<PathBuf> = changed.iter().map(|path| normalize(path)).collect();
+        let rendered_dir = normalize(&self.path.join(".dots"));
+
+        let settings_paths: Vec<PathBuf> = Settings::bombadil_config_xdg_path()
+            .into_iter()
+            .chain(self.imports.iter().cloned())
+            .map(|path| normalize(&path))
+            .collect();
+
+        if changed.iter().any(|path| settings_paths.contains(path)) {
+            return Changes::Settings;
+        }
+
+        let var_paths: Vec<PathBuf> = self
+            .var_paths
+            .iter()
+            .map(|path| normalize(&self.resolve_path(path)))
+            .collect();
+
+        let watch_paths: Vec<PathBuf> = self
+            .watch
+            .paths
+            .iter()
+            .map(|path| normalize(&self.resolve_path(path)))
+            .collect();
+
+        if changed.iter().any(|path| {
+            var_paths.contains(path) || watch_paths.iter().any(|watched| path.starts_with(watched))
+        }) {
+            return Changes::Vars;
+        }
+
+        let dots = self
+            .dots
+            .iter()
+            .filter(|(key, dot)| {
+                let source = normalize(&self.path.join(&dot.source));
+                let local_vars: Vec<PathBuf> = self
+                    .get_auto_ignored_files(key)
+                    .iter()
+                    .map(|path| normalize(path))
+                    .collect();
+
+                changed
+                    .iter()
+                    // Rendered dots live inside the dotfiles directory, changes there are our own
+                    .filter(|path| !path.starts_with(&rendered_dir))
+                    .any(|path| path.starts_with(&source) || local_vars.contains(path))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        Changes::Dots(dots)
+    }
+}
+
+// Resolve symlinks so changed paths can be compared with settings paths,
+// removed files cannot be canonicalized, so we fall back to their parent directory.
+fn normalize(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => normalize(parent).join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_helpers::setup;
+    use crate::watch::Changes;
+    use crate::Bombadil;
+    use crate::Mode::NoGpg;
+    use anyhow::{anyhow, Result};
+    use sealed_test::prelude::*;
+    use speculoos::prelude::*;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::{env, fs};
+
+    fn changed(path: &str) -> Vec<PathBuf> {
+        vec![env::current_dir()
+            .unwrap()
+            .join("dotfiles_watch")
+            .join(path)]
+    }
+
+    fn dots(keys: &[&str]) -> Changes {
+        Changes::Dots(keys.iter().map(ToString::to_string).collect())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_map_source_change_to_dot() -> Result<()> {
+        let bombadil = Bombadil::from_settings(NoGpg)?;
+
+        assert_that!(bombadil.changes(&changed("sway/config"))).is_equal_to(dots(&["sway"]));
+        assert_that!(bombadil.changes(&changed("zsh/zshrc"))).is_equal_to(dots(&["zsh"]));
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_map_local_vars_change_to_dot() -> Result<()> {
+        let bombadil = Bombadil::from_settings(NoGpg)?;
+
+        assert_that!(bombadil.changes(&changed("sway/vars.toml"))).is_equal_to(dots(&["sway"]));
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_reload_vars_on_global_vars_change() -> Result<()> {
+        let bombadil = Bombadil::from_settings(NoGpg)?;
+
+        assert_that!(bombadil.changes(&changed("vars.toml"))).is_equal_to(Changes::Vars);
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_reload_settings_on_config_or_import_change() -> Result<()> {
+        let bombadil = Bombadil::from_settings(NoGpg)?;
+
+        assert_that!(bombadil.changes(&changed("bombadil.toml"))).is_equal_to(Changes::Settings);
+        assert_that!(bombadil.changes(&changed("import.toml"))).is_equal_to(Changes::Settings);
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_ignore_rendered_dots_changes() -> Result<()> {
+        let bombadil = Bombadil::from_settings(NoGpg)?;
+
+        assert_that!(bombadil.changes(&changed(".dots/sway/config"))).is_equal_to(dots(&[]));
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_watch_paths_outside_dotfiles_dir() -> Result<()> {
+        // Arrange
+        let home = env::current_dir()?;
+        fs::create_dir_all(".cache/wal")?;
+        fs::write(".cache/wal/colors.toml", "background = \"#000000\"")?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.watch.paths = vec![PathBuf::from("~/.cache/wal")];
+        bombadil.var_paths.push(home.join(".cache/wal/colors.toml"));
+
+        // Act
+        let watched = bombadil.watched_paths();
+
+        // Assert
+        assert_that!(watched).contains(home.join("dotfiles_watch"));
+        assert_that!(watched).contains(home.join(".cache/wal"));
+        assert_that!(watched).has_length(2);
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_reload_vars_on_watched_path_change() -> Result<()> {
+        // Arrange
+        fs::create_dir_all(".cache/wal")?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.watch.paths = vec![PathBuf::from("~/.cache/wal")];
+        let changed = vec![env::current_dir()?.join(".cache/wal/colors.json")];
+
+        // Act
+        let changes = bombadil.changes(&changed);
+
+        // Assert
+        assert_that!(changes).is_equal_to(Changes::Vars);
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_reload_vars_on_home_var_file_change() -> Result<()> {
+        // Arrange
+        fs::create_dir_all(".cache/wal")?;
+        fs::write(".cache/wal/colors.toml", "background = \"#000000\"")?;
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil
+            .var_paths
+            .push(PathBuf::from("~/.cache/wal/colors.toml"));
+        fs::write(".cache/wal/colors.toml", "background = \"#ffffff\"")?;
+        let changed = vec![env::current_dir()?.join(".cache/wal/colors.toml")];
+
+        // Act
+        let changes = bombadil.changes(&changed);
+        bombadil.reload_vars()?;
+
+        // Assert
+        assert_that!(changes).is_equal_to(Changes::Vars);
+        assert_that!(bombadil.vars.inner.get("background"))
+            .is_some()
+            .is_equal_to(&serde_json::json!("#ffffff"));
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_keep_settings_on_reload_error() -> Result<()> {
+        // Arrange
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        fs::write(
+            "dotfiles_watch/bombadil.toml",
+            "dotfiles_dir = \"dotfiles_watch",
+        )?;
+
+        // Act
+        let result = bombadil.on_changes(&changed("bombadil.toml"), &[], false);
+
+        // Assert
+        assert_that!(result).is_err();
+        assert_that!(bombadil.dots.get("sway")).is_some();
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_notify_errors() -> Result<()> {
+        // Arrange
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+        bombadil.watch.notify = Some("echo \"$BOMBADIL_ERROR\" > notified".to_string());
+
+        // Act
+        bombadil.report_error(&anyhow!("template error"));
+
+        // Assert
+        let notification = fs::read_to_string("notified")?;
+        assert_that!(notification).contains("template error");
+        Ok(())
+    }
+
+    #[sealed_test(files = ["tests/dotfiles_watch"], before = setup("dotfiles_watch"))]
+    fn should_install_only_affected_dots() -> Result<()> {
+        let mut bombadil = Bombadil::from_settings(NoGpg)?;
+
+        bombadil.install_dots(&HashSet::from(["sway".to_string()]), false)?;
+
+        assert_that!(PathBuf::from(".config/sway/config")).exists();
+        assert_that!(PathBuf::from(".zshrc")).does_not_exist();
+        Ok(())
+    }
+}